@@ -0,0 +1,196 @@
+//! Conditional content in documents
+//!
+//! A fenced block like
+//!
+//!     ```docdustry-if variant = pro
+//!     Only in the pro variant.
+//!     ```
+//!
+//! or HTML comments like `<!-- if variant = pro -->`, `<!-- else -->`, `<!-- endif -->`
+//! keep or drop their Markdown depending on a query expression.
+
+use log::warn;
+use std::collections::BTreeMap;
+
+use crate::config::Config;
+use crate::md_lines::fence;
+use crate::query::{Expr, Fields};
+
+/// What conditions can refer to
+pub struct Context<'a> {
+    pub cfg: &'a Config,
+    /// variables defined by the document
+    pub doc_vars: &'a BTreeMap<String, String>,
+}
+
+impl Fields for Context<'_> {
+    fn field_values(&self, key: &str) -> Vec<String> {
+        match key {
+            "variant" => self.cfg.variant_include.clone(),
+            _ => self
+                .doc_vars
+                .get(key)
+                .or_else(|| self.cfg.vars.get(key))
+                .map(|v| vec![v.clone()])
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// A directive comment like `<!-- if expr -->` with its content
+fn directive(line: &str) -> Option<&str> {
+    line.trim_end()
+        .strip_prefix("<!--")?
+        .strip_suffix("-->")
+        .map(|s| s.trim())
+}
+
+fn eval(expr: &str, ctx: &Context, did: &str) -> bool {
+    match Expr::parse(expr) {
+        Ok(e) => e.matches(ctx),
+        Err(e) => {
+            warn!("Invalid condition in {}: {}: {}", did, expr, e);
+            false
+        }
+    }
+}
+
+/// Remove the Markdown whose conditions are false
+pub fn apply(raw: &str, ctx: &Context, did: &str) -> String {
+    if !raw.contains("docdustry-if") && !raw.contains("<!-- if ") {
+        return raw.to_string();
+    }
+    let mut ret = String::with_capacity(raw.len());
+    // for each open comment conditional: whether its current branch is kept
+    let mut stack: Vec<bool> = vec![];
+    let mut lines = raw.split_inclusive('\n');
+    while let Some(line) = lines.next() {
+        let keep = stack.iter().all(|k| *k);
+        if let Some((c, len, info)) = fence(line) {
+            // the content of a code block is copied verbatim,
+            // a conditional block is copied if the condition holds
+            let cond = info.strip_prefix("docdustry-if ").map(|e| e.trim());
+            let mut content = String::new();
+            // code blocks opened inside a conditional block
+            let mut nested = 0;
+            for inner in lines.by_ref() {
+                match fence(inner) {
+                    Some((c2, len2, "")) if c2 == c && len2 >= len && nested == 0 => {
+                        if cond.is_none() {
+                            content.push_str(inner);
+                        }
+                        break;
+                    }
+                    Some((_, _, "")) if nested > 0 => nested -= 1,
+                    Some(_) if cond.is_some() => nested += 1,
+                    _ => (),
+                }
+                content.push_str(inner);
+            }
+            match cond {
+                Some(expr) => {
+                    if keep && eval(expr, ctx, did) {
+                        ret.push_str(&apply(&content, ctx, did));
+                    }
+                }
+                None => {
+                    if keep {
+                        ret.push_str(line);
+                        ret.push_str(&content);
+                    }
+                }
+            }
+            continue;
+        }
+        match directive(line) {
+            Some(d) if d.starts_with("if ") => {
+                stack.push(keep && eval(&d[3..], ctx, did));
+            }
+            Some("else") => match stack.pop() {
+                Some(k) => {
+                    let outer = stack.iter().all(|k| *k);
+                    stack.push(outer && !k);
+                }
+                None => warn!("<!-- else --> without <!-- if --> in {}", did),
+            },
+            Some("endif") => {
+                if stack.pop().is_none() {
+                    warn!("<!-- endif --> without <!-- if --> in {}", did);
+                }
+            }
+            _ => {
+                if keep {
+                    ret.push_str(line);
+                }
+            }
+        }
+    }
+    if !stack.is_empty() {
+        warn!("<!-- if --> without <!-- endif --> in {}", did);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply_pro(raw: &str) -> String {
+        let mut cfg = Config::new();
+        cfg.set_variant("pro");
+        cfg.vars.insert("version".to_string(), "1.4".to_string());
+        let doc_vars = BTreeMap::from([("edition".to_string(), "gold".to_string())]);
+        apply(
+            raw,
+            &Context {
+                cfg: &cfg,
+                doc_vars: &doc_vars,
+            },
+            "test",
+        )
+    }
+
+    #[test]
+    fn fenced_blocks() {
+        assert_eq!(
+            apply_pro("a\n```docdustry-if variant = pro\nb\n```\n```docdustry-if variant = lite\nc\n```\nd\n"),
+            "a\nb\nd\n"
+        );
+        // indented like in a list item
+        assert_eq!(
+            apply_pro("- a\n  ```docdustry-if variant = lite\n  b\n  ```\n"),
+            "- a\n"
+        );
+        // code blocks inside need a longer fence
+        assert_eq!(
+            apply_pro("````docdustry-if variant = pro\n```sh\nx\n```\n````\n"),
+            "```sh\nx\n```\n"
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let raw = "<!-- if variant = lite -->\na\n<!-- else -->\nb\n<!-- endif -->\nc\n";
+        assert_eq!(apply_pro(raw), "b\nc\n");
+        let nested = "<!-- if variant = lite -->\n<!-- if version = 1.4 -->\na\n<!-- else -->\nb\n<!-- endif -->\n<!-- endif -->\nc\n";
+        assert_eq!(apply_pro(nested), "c\n");
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(
+            apply_pro("<!-- if version = 1.4 and edition = gold -->\na\n<!-- endif -->\n"),
+            "a\n"
+        );
+        assert_eq!(
+            apply_pro("<!-- if version = 2 -->\na\n<!-- endif -->\n"),
+            ""
+        );
+    }
+
+    #[test]
+    fn code_is_left_alone() {
+        let raw = "```md\n<!-- if variant = lite -->\n```\na\n";
+        assert_eq!(apply_pro(raw), raw);
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
 
 pub struct Config {
@@ -6,6 +7,93 @@ pub struct Config {
     pub db_path: PathBuf,
     pub frontpage: Option<String>,
     pub theme: Option<PathBuf>,
+    pub inline_svg: bool,
+    /// document types by name, `default` for documents without type
+    pub types: HashMap<String, DocType>,
+    /// statuses of documents which are done
+    pub released: Vec<String>,
+    /// statuses of documents which are not relevant anymore
+    pub inactive: Vec<String>,
+    pub inactive_mode: InactiveMode,
+    /// documents must have one of these tags, if any
+    pub variant_include: Vec<String>,
+    /// documents must not have any of these tags
+    pub variant_exclude: Vec<String>,
+    /// values for `{{name}}` in documents
+    pub vars: BTreeMap<String, String>,
+}
+
+/// What to do with documents having an inactive status
+#[derive(PartialEq)]
+pub enum InactiveMode {
+    /// leave them out of the output
+    Exclude,
+    /// mark them with a banner
+    Watermark,
+}
+
+/// Settings for documents with a `type` in their doc meta info
+pub struct DocType {
+    pub lifecycle: Option<Lifecycle>,
+    /// for generated DIDs like `REQ-0042`
+    pub prefix: Option<String>,
+    /// where new documents are created
+    pub dir: Option<PathBuf>,
+    /// Markdown file for new documents
+    pub template: Option<PathBuf>,
+    /// status of new documents
+    pub status: Option<String>,
+}
+
+impl DocType {
+    pub fn new() -> DocType {
+        DocType {
+            lifecycle: None,
+            prefix: None,
+            dir: None,
+            template: None,
+            status: None,
+        }
+    }
+}
+
+/// The statuses a document goes through
+pub struct Lifecycle {
+    /// all statuses in order of appearance
+    pub statuses: Vec<String>,
+    /// allowed status changes
+    pub transitions: Vec<(String, String)>,
+}
+
+impl Lifecycle {
+    /// Parse chains like `draft -> review -> accepted, review -> draft`
+    pub fn parse(s: &str) -> Lifecycle {
+        let mut lc = Lifecycle {
+            statuses: vec![],
+            transitions: vec![],
+        };
+        for chain in s.split(',') {
+            let mut prev: Option<String> = None;
+            for status in chain.split("->") {
+                let status = status.trim().to_string();
+                if status.is_empty() {
+                    continue;
+                }
+                if !lc.statuses.contains(&status) {
+                    lc.statuses.push(status.clone());
+                }
+                if let Some(p) = prev {
+                    lc.transitions.push((p, status.clone()));
+                }
+                prev = Some(status);
+            }
+        }
+        lc
+    }
+
+    pub fn allows(&self, from: &str, to: &str) -> bool {
+        from == to || self.transitions.iter().any(|(f, t)| f == from && t == to)
+    }
 }
 
 impl Config {
@@ -16,6 +104,14 @@ impl Config {
             db_path: PathBuf::from(&"db.sqlite3"),
             frontpage: None,
             theme: None,
+            inline_svg: false,
+            types: HashMap::new(),
+            released: vec!["accepted".to_string()],
+            inactive: vec![],
+            inactive_mode: InactiveMode::Watermark,
+            variant_include: vec![],
+            variant_exclude: vec![],
+            vars: BTreeMap::new(),
         }
     }
 
@@ -27,6 +123,62 @@ impl Config {
         }
     }
 
+    /// The lifecycle for documents of a type
+    pub fn lifecycle(&self, doc_type: &str) -> Option<&Lifecycle> {
+        let name = if doc_type.is_empty() {
+            "default"
+        } else {
+            doc_type
+        };
+        self.types.get(name)?.lifecycle.as_ref()
+    }
+
+    /// The prefix of generated DIDs for documents of a type
+    pub fn id_prefix(&self, doc_type: &str) -> String {
+        let name = if doc_type.is_empty() {
+            "default"
+        } else {
+            doc_type
+        };
+        match self.types.get(name).and_then(|t| t.prefix.clone()) {
+            Some(p) => p,
+            None => "DOC".to_string(),
+        }
+    }
+
+    pub fn is_released(&self, status: &str) -> bool {
+        self.released.iter().any(|s| s == status)
+    }
+
+    pub fn is_inactive(&self, status: &str) -> bool {
+        self.inactive.iter().any(|s| s == status)
+    }
+
+    /// Documents with this status are left out of the output
+    pub fn is_excluded(&self, status: &str) -> bool {
+        self.inactive_mode == InactiveMode::Exclude && self.is_inactive(status)
+    }
+
+    /// Set the variant from a list like `pro,-lite`
+    pub fn set_variant(&mut self, spec: &str) {
+        self.variant_include.clear();
+        self.variant_exclude.clear();
+        for tag in spec.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            match tag.strip_prefix('-') {
+                Some(t) => self.variant_exclude.push(t.to_string()),
+                None => self.variant_include.push(tag.to_string()),
+            }
+        }
+    }
+
+    /// Whether a document with these tags is part of the variant
+    pub fn in_variant(&self, tags: &[String]) -> bool {
+        if self.variant_exclude.iter().any(|t| tags.contains(t)) {
+            return false;
+        }
+        self.variant_include.is_empty() || self.variant_include.iter().any(|t| tags.contains(t))
+    }
+
     pub fn push_source_dir(&mut self, s: PathBuf) {
         self.sources.push(s)
     }
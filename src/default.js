@@ -7,11 +7,13 @@ window.addEventListener("load", (event) => {
     document.querySelector('body').classList.add("status-" + doc_status);
   }
 
-  // ids for top headline
+  // id for top headline, which has its own id for the table of contents
   const first_h1 = document.querySelector('h1');
   const doc_id = DOCDUSTRY_LOCALS["did"];
-  if (first_h1) {
-    first_h1.setAttribute("id", doc_id);
+  if (first_h1 && doc_id && !document.getElementById(doc_id)) {
+    const anchor = document.createElement("a");
+    anchor.setAttribute("id", doc_id);
+    first_h1.before(anchor);
   }
 
   const main = document.querySelector('section.main');
@@ -21,11 +23,20 @@ window.addEventListener("load", (event) => {
       var did = e.href;
       if (!did.startsWith("did:")) return;
       did = did.substring(4);
+      // deep link to a heading like did:foo#bar
+      var fragment = "";
+      const hash = did.indexOf("#");
+      if (hash >= 0) {
+        fragment = did.substring(hash);
+        did = did.substring(0, hash);
+      }
       for (var j = 0, len_j = DOCDUSTRY_GLOBALS.docs.length; j < len_j; j++) {
         const d = DOCDUSTRY_GLOBALS.docs[j];
         if (d.did == did) {
-          e.href = d.url;
-          e.title = d.title;
+          e.href = d.url + fragment;
+          if (!e.title) {
+            e.title = d.title;
+          }
           if (e.innerText == "") {
             e.textContent += d.title;
           }
@@ -35,68 +46,38 @@ window.addEventListener("load", (event) => {
     });
   }
 
-  // table of contents
-  if (main) {
-    const headings = main.querySelectorAll('h1, h2, h3, h4, h5, h6');
-    const tocContainer = document.createElement('section');
-    tocContainer.id = "docdustry-toc";
-    tocContainer.style = "float:right";
-    const dummy = document.createElement('div');
-    tocContainer.appendChild(dummy);
-    var currentLevel = 0;
-    let currentList = tocContainer;
-
-    headings.forEach((heading, index) => {
-      if (!heading.id) {
-        heading.id = "toc-" + index;
-      }
-
-      var level = parseInt(heading.tagName.charAt(1));
-
-      // Adjust level based on section nesting
-      var sectionCount = 0;
-      var parent = heading.parentElement;
-      while (parent && parent !== main) {
-        if (parent.tagName.toLowerCase() === 'article') {
-          sectionCount++;
-        }
-        parent = parent.parentElement;
-      }
-      level += sectionCount;
-
-
-      // Create new list if necessary
-      while (currentLevel < level) {
-        var newList = document.createElement('ul');
-        const last = currentList.lastElementChild;
-        if (last) {
-          currentList.lastElementChild.appendChild(newList);
-        } else {
-          const newItem = document.createElement('li');
-          currentList.appendChild(newItem);
-          newItem.appendChild(newList);
-        }
-        currentList = newList;
-        currentLevel++;
-      }
-
-      // Go up levels if necessary
-      while (currentLevel > level) {
-        currentList = currentList.parentElement.parentElement;
-        currentLevel--;
+  // doclist tables sorted by clicking a column header and filtered by text
+  document.querySelectorAll('table.doclist').forEach(function(table) {
+    const tbody = table.tBodies[0];
+    const filter = document.createElement('input');
+    filter.type = "text";
+    filter.classList.add("doclist-filter");
+    filter.placeholder = "Filter...";
+    filter.addEventListener('input', function() {
+      const txt = this.value.trim().toLowerCase();
+      for (const row of tbody.rows) {
+        row.hidden = !row.innerText.toLowerCase().includes(txt);
       }
-
-      // Create list item and link
-      var listItem = document.createElement('li');
-      var link = document.createElement('a');
-      link.textContent = heading.textContent;
-      link.href = '#' + heading.id;
-      listItem.appendChild(link);
-      currentList.appendChild(listItem);
-
     });
-    main.parentElement.insertBefore(tocContainer, main);
-  }
+    table.parentNode.insertBefore(filter, table);
+    table.querySelectorAll('th').forEach(function(th, col) {
+      th.addEventListener('click', function() {
+        const ascending = !th.classList.contains("sorted-asc");
+        table.querySelectorAll('th').forEach(h => h.classList.remove("sorted-asc", "sorted-desc"));
+        th.classList.add(ascending ? "sorted-asc" : "sorted-desc");
+        const rows = Array.from(tbody.rows);
+        rows.sort(function(a, b) {
+          const x = a.cells[col].innerText;
+          const y = b.cells[col].innerText;
+          const o = (x !== "" && y !== "" && !isNaN(x) && !isNaN(y))
+            ? Number(x) - Number(y)
+            : x.localeCompare(y);
+          return ascending ? o : -o;
+        });
+        rows.forEach(r => tbody.appendChild(r));
+      });
+    });
+  });
 
   const header = document.querySelector('header');
   if (header) {
@@ -199,7 +180,7 @@ window.addEventListener("load", (event) => {
           break;
         }
       }
-      for (const link of doc.includes) {
+      for (const link of doc.includes.concat(doc.listed)) {
         if (link === doc_id) {
           backlinks.push(doc);
           break;
@@ -0,0 +1,413 @@
+//! Normalizing the notation of the Markdown sources
+//!
+//! Only the notation changes, never the generated documents.
+//! Code blocks and HTML blocks are left alone.
+
+use ignore::Walk;
+use log::{error, info, warn};
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::gen_html::{meta_fields, CodeInfo};
+
+/// Order of keys in the doc meta info, other keys follow in their order
+const META_KEYS: [&str; 4] = ["id", "type", "status", "tag"];
+
+fn at_line_start(raw: &str, pos: usize) -> bool {
+    pos == 0 || raw[..pos].ends_with('\n')
+}
+
+/// Doc meta info with canonical key order and spacing, and sorted tags
+fn format_meta(text: &str) -> String {
+    let fields = meta_fields(text);
+    let mut tags: Vec<&str> = fields
+        .iter()
+        .filter(|(k, _)| *k == "tag")
+        .map(|(_, v)| *v)
+        .collect();
+    tags.sort();
+    tags.dedup();
+    let mut fields: Vec<(&str, &str)> = fields
+        .into_iter()
+        .filter(|(k, _)| *k != "tag")
+        .chain(tags.into_iter().map(|t| ("tag", t)))
+        .collect();
+    // stable, so repeated keys keep their order
+    fields.sort_by_key(|(k, _)| {
+        META_KEYS
+            .iter()
+            .position(|x| x == k)
+            .unwrap_or(META_KEYS.len())
+    });
+    let mut ret = String::new();
+    for (k, v) in fields {
+        ret.push_str(k);
+        ret.push(':');
+        if !v.is_empty() {
+            ret.push(' ');
+            ret.push_str(v);
+        }
+        ret.push('\n');
+    }
+    // ignored by the parser, but maybe valuable to the author
+    for line in text.lines() {
+        if !line.contains(':') && !line.trim().is_empty() {
+            ret.push_str(line.trim());
+            ret.push('\n');
+        }
+    }
+    ret
+}
+
+/// Whether an ATX heading with this text would lose a closing sequence of `#`
+fn has_closing_hashes(text: &str) -> bool {
+    let t = text.trim_end_matches('#');
+    t.len() < text.len() && (t.is_empty() || t.ends_with([' ', '\t']))
+}
+
+/// The heading in ATX style like `## Title`
+fn format_heading(src: &str, level: usize) -> String {
+    let lines: Vec<&str> = src.trim_end().lines().collect();
+    let text = if lines.len() > 1 {
+        // setext style, the last line is the underline
+        lines[..lines.len() - 1]
+            .iter()
+            .map(|l| l.trim())
+            .collect::<Vec<_>>()
+            .join(" ")
+    } else {
+        let text = src.trim().trim_start_matches('#').trim();
+        if has_closing_hashes(text) {
+            text.trim_end_matches('#').trim_end().to_string()
+        } else {
+            text.to_string()
+        }
+    };
+    let mut ret = "#".repeat(level);
+    if !text.is_empty() {
+        ret.push(' ');
+        ret.push_str(&text);
+        if has_closing_hashes(&text) {
+            ret.push_str(" #");
+        }
+    }
+    ret
+}
+
+/// Whether a word at the start of a line could not start another block
+fn can_start_line(word: &str) -> bool {
+    let rest = word.trim_start_matches(|c: char| c.is_ascii_digit());
+    if rest.len() < word.len() && rest.starts_with(['.', ')']) {
+        return false;
+    }
+    !word.starts_with(['#', '>', '-', '+', '*', '=', '_', '`', '~', '<'])
+}
+
+/// The paragraph wrapped at a width, or None if whitespace in it matters
+fn wrap_paragraph(src: &str, width: usize) -> Option<String> {
+    if src.lines().any(|l| {
+        l.trim().contains(|c: char| c.is_whitespace() && c != ' ') || l.trim().contains("  ")
+    }) {
+        return None;
+    }
+    let mut ret = String::new();
+    let mut line_len = 0;
+    for word in src.split_whitespace() {
+        let len = word.chars().count();
+        if line_len == 0 {
+            // first word
+        } else if line_len + 1 + len > width && can_start_line(word) && !ret.ends_with('\\') {
+            ret.push('\n');
+            line_len = 0;
+        } else {
+            ret.push(' ');
+            line_len += 1;
+        }
+        ret.push_str(word);
+        line_len += len;
+    }
+    Some(ret)
+}
+
+/// Replace the ranges, which must be in order
+fn apply_edits(raw: &str, edits: &[(Range<usize>, String)]) -> String {
+    let mut ret = String::with_capacity(raw.len());
+    let mut copied = 0;
+    for (r, new) in edits {
+        ret.push_str(&raw[copied..r.start]);
+        ret.push_str(new);
+        copied = r.end;
+    }
+    ret.push_str(&raw[copied..]);
+    ret
+}
+
+/// A hard line break of trailing spaces as a backslash, which is visible
+fn hard_break_edit(raw: &str, r: Range<usize>) -> Option<(Range<usize>, String)> {
+    if !raw[r.clone()].starts_with([' ', '\t']) {
+        return None;
+    }
+    let end = r.start + raw[r.clone()].trim_end().len();
+    Some((r.start..end, "\\".to_string()))
+}
+
+/// Normalize doc meta info, headings, hard line breaks, and optionally wrap paragraphs
+fn format_blocks(raw: &str, wrap: Option<usize>) -> String {
+    let mut edits: Vec<(Range<usize>, String)> = vec![];
+    // lists, block quotes, and footnotes around the current event
+    let mut containers = 0;
+    let mut parser = Parser::new_ext(raw, Options::ENABLE_HEADING_ATTRIBUTES).into_offset_iter();
+    while let Some((event, range)) = parser.next() {
+        match event {
+            Event::Start(Tag::BlockQuote | Tag::List(_) | Tag::FootnoteDefinition(_)) => {
+                containers += 1
+            }
+            Event::End(TagEnd::BlockQuote | TagEnd::List(_) | TagEnd::FootnoteDefinition) => {
+                containers -= 1
+            }
+            Event::Start(Tag::Heading { level, .. }) => {
+                let mut hard_break = false;
+                for (e, r) in parser.by_ref() {
+                    match e {
+                        Event::End(TagEnd::Heading(_)) => break,
+                        Event::HardBreak => {
+                            hard_break = true;
+                            edits.extend(hard_break_edit(raw, r));
+                        }
+                        _ => (),
+                    }
+                }
+                if !hard_break && at_line_start(raw, range.start) {
+                    let src = &raw[range.clone()];
+                    let end = range.start + src.trim_end().len();
+                    edits.push((range.start..end, format_heading(src, level as usize)));
+                }
+            }
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+                let mut text = String::new();
+                for (e, _) in parser.by_ref() {
+                    match e {
+                        Event::End(TagEnd::CodeBlock) => break,
+                        Event::Text(t) => text.push_str(&t),
+                        _ => (),
+                    }
+                }
+                if CodeInfo::parse(&info).lang != "docdustry-docmeta"
+                    || !at_line_start(raw, range.start)
+                {
+                    continue;
+                }
+                let src = &raw[range.clone()];
+                let open = src.lines().next().unwrap_or("").trim_end();
+                let c = open.chars().next().unwrap_or('`');
+                let close = c
+                    .to_string()
+                    .repeat(open.chars().take_while(|x| *x == c).count());
+                let end = range.start + src.trim_end().len();
+                let new = format!("{}\n{}{}", open, format_meta(&text), close);
+                edits.push((range.start..end, new));
+            }
+            Event::Start(Tag::Paragraph) => {
+                let mut hard_break = false;
+                for (e, r) in parser.by_ref() {
+                    match e {
+                        Event::End(TagEnd::Paragraph) => break,
+                        Event::HardBreak => {
+                            hard_break = true;
+                            edits.extend(hard_break_edit(raw, r));
+                        }
+                        _ => (),
+                    }
+                }
+                let Some(width) = wrap else {
+                    continue;
+                };
+                if hard_break || containers > 0 || !at_line_start(raw, range.start) {
+                    continue;
+                }
+                let src = raw[range.clone()].trim_end();
+                if let Some(new) = wrap_paragraph(src, width) {
+                    edits.push((range.start..range.start + src.len(), new));
+                }
+            }
+            // in tight list items without paragraph
+            Event::HardBreak => edits.extend(hard_break_edit(raw, range)),
+            _ => (),
+        }
+    }
+    apply_edits(raw, &edits)
+}
+
+/// Remove trailing whitespace outside of code and HTML blocks
+fn strip_trailing_whitespace(raw: &str) -> String {
+    let mut keep: Vec<Range<usize>> = vec![];
+    for (event, range) in
+        Parser::new_ext(raw, Options::ENABLE_HEADING_ATTRIBUTES).into_offset_iter()
+    {
+        if let Event::Start(Tag::CodeBlock(_) | Tag::HtmlBlock) = event {
+            keep.push(range);
+        }
+    }
+    let mut ret = String::with_capacity(raw.len());
+    let mut offset = 0;
+    for line in raw.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+        // indented code starts after the indentation
+        if keep.iter().any(|r| r.start < offset && start < r.end) {
+            ret.push_str(line);
+        } else {
+            ret.push_str(line.trim_end());
+            if line.ends_with('\n') {
+                ret.push('\n');
+            }
+        }
+    }
+    if !ret.is_empty() && !ret.ends_with('\n') {
+        ret.push('\n');
+    }
+    ret
+}
+
+/// The Markdown in canonical notation
+pub fn format(raw: &str, wrap: Option<usize>) -> String {
+    strip_trailing_whitespace(&format_blocks(raw, wrap))
+}
+
+fn md_files(src_path_base: &Path) -> Vec<PathBuf> {
+    let mut ret = vec![];
+    for result in Walk::new(src_path_base) {
+        match result {
+            Ok(entry) => {
+                let p = entry.path();
+                if p.is_file() && p.extension().is_some_and(|ext| ext == "md") {
+                    ret.push(p.to_path_buf());
+                }
+            }
+            Err(err) => error!("Not an entry: {}", err),
+        }
+    }
+    ret
+}
+
+pub(crate) fn cmd_fmt(cfg: &Config, check: bool, wrap: Option<usize>) {
+    let mut unformatted = 0;
+    for src in cfg.get_sources() {
+        for path in md_files(&src) {
+            let raw = match fs::read_to_string(&path) {
+                Ok(raw) => raw,
+                Err(e) => {
+                    error!("Failed to read {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let formatted = format(&raw, wrap);
+            if formatted == raw {
+                continue;
+            }
+            unformatted += 1;
+            if check {
+                println!("{}", path.display());
+            } else if let Err(e) = fs::write(&path, formatted) {
+                warn!("Failed to write {}: {}", path.display(), e);
+            } else {
+                info!("Formatted {}", path.display());
+            }
+        }
+    }
+    if check && unformatted > 0 {
+        error!("{} files are not formatted", unformatted);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pulldown_cmark::html;
+
+    const SAMPLE: &str = "Title
+=====
+
+## Section ##
+
+A paragraph with a hard break  
+and a second line which is long enough to be wrapped somewhere.
+
+- tight item  
+  with a break
+- item\\
+  with a backslash
+
+* loose item
+
+  with a break  
+  in a paragraph
+
+> quoted  
+> break
+
+```rust
+let x = 1;   
+```
+
+<div>
+html  
+</div>
+
+    indented code  
+";
+
+    fn to_html(raw: &str) -> String {
+        let mut ret = String::new();
+        html::push_html(
+            &mut ret,
+            Parser::new_ext(raw, Options::ENABLE_HEADING_ATTRIBUTES),
+        );
+        ret
+    }
+
+    #[test]
+    fn keeps_html() {
+        assert_eq!(to_html(&format(SAMPLE, None)), to_html(SAMPLE));
+        // wrapping only moves soft breaks
+        let words = |s: &str| s.split_whitespace().collect::<Vec<_>>().join(" ");
+        assert_eq!(
+            words(&to_html(&format(SAMPLE, Some(20)))),
+            words(&to_html(SAMPLE))
+        );
+    }
+
+    #[test]
+    fn idempotent() {
+        for wrap in [None, Some(20)] {
+            let once = format(SAMPLE, wrap);
+            assert_eq!(format(&once, wrap), once);
+        }
+    }
+
+    #[test]
+    fn hard_breaks() {
+        let out = format(SAMPLE, None);
+        assert!(out.contains("- tight item\\\n  with a break"));
+        assert!(out.contains("  with a break\\\n  in a paragraph"));
+        assert!(out.contains("> quoted\\\n"));
+        assert!(out.contains("let x = 1;   \n"));
+        assert!(out.contains("html  \n"));
+    }
+
+    #[test]
+    fn headings_and_meta() {
+        let out = format(
+            "Title\n=====\n\n## Section ##\n\n```docdustry-docmeta\ntag: b\nstatus:draft\nid: x\ntag: a\n```\n",
+            None,
+        );
+        assert_eq!(
+            out,
+            "# Title\n\n## Section\n\n```docdustry-docmeta\nid: x\nstatus: draft\ntag: a\ntag: b\n```\n"
+        );
+    }
+}
@@ -1,56 +1,505 @@
+use log::{error, info};
+use std::collections::{BTreeMap, HashSet};
 use std::path::PathBuf;
+use std::process::Command;
 
-use sqlite::{Connection, Result};
+use sqlite::{Connection, OpenFlags, Result, State};
 
 use crate::{
     config::Config,
-    gen_html::{read_md_files, Doc},
+    gen_html::{read_all_md_files, Doc},
+    glossary::{self, Term},
 };
 
-pub(crate) fn cmd_gen_db(cfg: &Config) {
+pub(crate) fn cmd_gen_db(cfg: &Config, snapshot: bool) {
     let db = init_db(&cfg.db_path).unwrap();
     let mut docs: Vec<Doc> = vec![];
     for src in cfg.get_sources() {
-        read_md_files(&mut docs, src.as_path());
+        // all variants, so one database serves all of them
+        read_all_md_files(&mut docs, src.as_path(), cfg);
     }
     db.execute("BEGIN TRANSACTION;").expect("begin");
+    let mut updated = 0;
     for d in &docs {
-        let query = "INSERT INTO documents (did,raw) VALUES (?,?);";
-        let mut stmt = db.prepare(query).unwrap();
-        stmt.bind((1, d.did.as_str())).unwrap();
-        stmt.bind((2, d.raw.as_str())).unwrap();
-        stmt.next().unwrap();
+        if update_doc(&db, d).expect("update document") {
+            updated += 1;
+        }
+    }
+    let deleted = delete_missing(&db, &docs).expect("delete documents");
+    update_definitions(&db, &glossary::collect(&docs)).expect("update definitions");
+    if snapshot {
+        let id = create_snapshot(&db).expect("snapshot");
+        println!("snapshot {}", id);
     }
     db.execute("COMMIT;").expect("commit");
+    info!(
+        "Database: {} documents, {} updated, {} deleted",
+        docs.len(),
+        updated,
+        deleted
+    );
+}
+
+/// Record the current documents, returns the snapshot id
+fn create_snapshot(db: &Connection) -> Result<i64> {
+    let mut stmt = db.prepare("INSERT INTO snapshots (git_rev) VALUES (?);")?;
+    stmt.bind((1, git_rev().as_str()))?;
+    stmt.next()?;
+    let mut stmt = db.prepare("SELECT last_insert_rowid();")?;
+    stmt.next()?;
+    let id: i64 = stmt.read(0)?;
+    let mut stmt = db.prepare(
+        "INSERT INTO snapshot_docs (snapshot_id,did,title,status,hash)
+        SELECT ?, did, title, status, hash FROM documents;",
+    )?;
+    stmt.bind((1, id))?;
+    stmt.next()?;
+    Ok(id)
+}
+
+/// The current git revision, empty if not available
+fn git_rev() -> String {
+    match Command::new("git").args(["rev-parse", "HEAD"]).output() {
+        Ok(out) if out.status.success() => String::from_utf8_lossy(&out.stdout).trim().to_string(),
+        _ => String::new(),
+    }
+}
+
+/// A document as recorded in a snapshot
+struct SnapshotDoc {
+    title: String,
+    status: String,
+    hash: String,
+}
+
+fn read_snapshot(db: &Connection, id: i64) -> Result<BTreeMap<String, SnapshotDoc>> {
+    let mut stmt =
+        db.prepare("SELECT did, title, status, hash FROM snapshot_docs WHERE snapshot_id = ?;")?;
+    stmt.bind((1, id))?;
+    let mut ret = BTreeMap::new();
+    while let State::Row = stmt.next()? {
+        ret.insert(
+            stmt.read::<String, _>(0)?,
+            SnapshotDoc {
+                title: stmt.read(1)?,
+                status: stmt.read(2)?,
+                hash: stmt.read(3)?,
+            },
+        );
+    }
+    Ok(ret)
+}
+
+/// List added, removed, and changed documents between two snapshots
+pub(crate) fn cmd_diff(cfg: &Config, from: i64, to: i64) {
+    if !cfg.db_path.exists() {
+        error!("No database at {}, see gen-db", cfg.db_path.display());
+        std::process::exit(1);
+    }
+    let result = Connection::open_with_flags(&cfg.db_path, OpenFlags::new().with_read_only())
+        .and_then(|db| diff(&db, from, to));
+    if let Err(e) = result {
+        error!("Failed to compare snapshots: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn diff(db: &Connection, from: i64, to: i64) -> Result<()> {
+    for id in [from, to] {
+        let mut stmt = db.prepare("SELECT timestamp, git_rev FROM snapshots WHERE id = ?;")?;
+        stmt.bind((1, id))?;
+        match stmt.next()? {
+            State::Row => println!(
+                "snapshot {}: {} {}",
+                id,
+                stmt.read::<String, _>(0)?,
+                stmt.read::<String, _>(1)?
+            ),
+            State::Done => {
+                error!("Unknown snapshot: {}", id);
+                std::process::exit(1);
+            }
+        }
+    }
+    let old = read_snapshot(db, from)?;
+    let new = read_snapshot(db, to)?;
+    for (did, d) in &new {
+        match old.get(did) {
+            None => println!("added {}: {}", did, d.title),
+            Some(o) => {
+                if o.hash != d.hash {
+                    println!("changed {}: {}", did, d.title);
+                }
+                if o.status != d.status {
+                    println!("status {}: {} -> {}", did, o.status, d.status);
+                }
+            }
+        }
+    }
+    for (did, d) in &old {
+        if !new.contains_key(did) {
+            println!("removed {}: {}", did, d.title);
+        }
+    }
+    Ok(())
+}
+
+/// Replace the glossary terms
+fn update_definitions(db: &Connection, terms: &[Term]) -> Result<()> {
+    db.execute("DELETE FROM definitions;")?;
+    for t in terms {
+        let mut stmt =
+            db.prepare("INSERT INTO definitions (term,definition,did) VALUES (?,?,?);")?;
+        stmt.bind((1, t.term.as_str()))?;
+        stmt.bind((2, t.definition.as_str()))?;
+        stmt.bind((3, t.did.as_str()))?;
+        stmt.next()?;
+    }
+    Ok(())
+}
+
+/// Content hash to detect changes since the last export
+fn doc_hash(d: &Doc) -> String {
+    let mut ctx = md5::Context::new();
+    ctx.consume(d.raw.as_bytes());
+    ctx.consume(d.html.as_bytes());
+    ctx.consume(serde_json::to_string(d).unwrap_or_default().as_bytes());
+    format!("{:x}", ctx.compute())
+}
+
+/// Delete documents which are not there anymore, returns how many
+fn delete_missing(db: &Connection, docs: &[Doc]) -> Result<usize> {
+    let current: HashSet<&str> = docs.iter().map(|d| d.did.as_str()).collect();
+    let mut missing: Vec<String> = vec![];
+    let mut stmt = db.prepare("SELECT did FROM documents;")?;
+    while let State::Row = stmt.next()? {
+        let did: String = stmt.read(0)?;
+        if !current.contains(did.as_str()) {
+            missing.push(did);
+        }
+    }
+    for did in &missing {
+        info!("Delete from database: {}", did);
+        let mut stmt = db.prepare("DELETE FROM documents WHERE did = ?;")?;
+        stmt.bind((1, did.as_str()))?;
+        stmt.next()?;
+    }
+    Ok(missing.len())
+}
+
+/// Insert or update a document with its tags, metadata, links, includes, and media.
+/// Returns false if it did not change.
+fn update_doc(db: &Connection, d: &Doc) -> Result<bool> {
+    let hash = doc_hash(d);
+    let mut stmt = db.prepare("SELECT hash FROM documents WHERE did = ?;")?;
+    stmt.bind((1, d.did.as_str()))?;
+    if let State::Row = stmt.next()? {
+        if stmt.read::<String, _>(0)? == hash {
+            return Ok(false);
+        }
+    }
+    let query = "INSERT INTO documents (did,title,status,type,path,url,html,raw,hash)
+        VALUES (?,?,?,?,?,?,?,?,?)
+        ON CONFLICT (did) DO UPDATE SET title=excluded.title, status=excluded.status,
+            type=excluded.type, path=excluded.path, url=excluded.url,
+            html=excluded.html, raw=excluded.raw, hash=excluded.hash;";
+    let mut stmt = db.prepare(query)?;
+    stmt.bind((1, d.did.as_str()))?;
+    stmt.bind((2, d.title.as_str()))?;
+    stmt.bind((3, d.status.as_str()))?;
+    stmt.bind((4, d.doc_type.as_str()))?;
+    stmt.bind((5, d.src_path_rel.to_string_lossy().as_ref()))?;
+    stmt.bind((6, d.url.as_str()))?;
+    stmt.bind((7, d.html.as_str()))?;
+    stmt.bind((8, d.raw.as_str()))?;
+    stmt.bind((9, hash.as_str()))?;
+    stmt.next()?;
+    let mut stmt = db.prepare("SELECT id FROM documents WHERE did = ?;")?;
+    stmt.bind((1, d.did.as_str()))?;
+    stmt.next()?;
+    let id: i64 = stmt.read(0)?;
+    for table in ["tags", "meta", "links", "includes", "media", "requirements"] {
+        let mut stmt = db.prepare(format!("DELETE FROM {} WHERE doc_id = ?;", table))?;
+        stmt.bind((1, id))?;
+        stmt.next()?;
+    }
+
+    for tag in &d.tags {
+        let mut stmt = db.prepare("INSERT OR IGNORE INTO tags (doc_id,tag) VALUES (?,?);")?;
+        stmt.bind((1, id))?;
+        stmt.bind((2, tag.as_str()))?;
+        stmt.next()?;
+    }
+    for (key, values) in &d.meta {
+        for value in values {
+            let mut stmt = db.prepare("INSERT INTO meta (doc_id,key,value) VALUES (?,?,?);")?;
+            stmt.bind((1, id))?;
+            stmt.bind((2, key.as_str()))?;
+            stmt.bind((3, value.as_str()))?;
+            stmt.next()?;
+        }
+    }
+    for (target, fragment, link_type) in typed_links(d) {
+        let mut stmt =
+            db.prepare("INSERT INTO links (doc_id,target,fragment,type) VALUES (?,?,?,?);")?;
+        stmt.bind((1, id))?;
+        stmt.bind((2, target))?;
+        stmt.bind((3, fragment))?;
+        stmt.bind((4, link_type))?;
+        stmt.next()?;
+    }
+    for (i, did) in d.includes.iter().enumerate() {
+        let mut stmt =
+            db.prepare("INSERT INTO includes (doc_id,target,position) VALUES (?,?,?);")?;
+        stmt.bind((1, id))?;
+        stmt.bind((2, did.as_str()))?;
+        stmt.bind((3, i as i64))?;
+        stmt.next()?;
+    }
+    for media in &d.media {
+        let mut stmt = db.prepare("INSERT INTO media (doc_id,path) VALUES (?,?);")?;
+        stmt.bind((1, id))?;
+        stmt.bind((2, media.to_string_lossy().as_ref()))?;
+        stmt.next()?;
+    }
+    if d.doc_type == "requirement" || d.tags.iter().any(|t| t == "requirement") {
+        let mut stmt =
+            db.prepare("INSERT INTO requirements (doc_id,description,status) VALUES (?,?,?);")?;
+        stmt.bind((1, id))?;
+        stmt.bind((2, d.title.as_str()))?;
+        stmt.bind((3, d.status.as_str()))?;
+        stmt.next()?;
+    }
+    Ok(true)
+}
+
+/// Links of a document as target, fragment, and type.
+/// Links in the text have type `link` to a DID or `url` otherwise,
+/// relations in the doc meta info are typed by their key.
+fn typed_links(d: &Doc) -> Vec<(&str, &str, &str)> {
+    let mut ret = vec![];
+    for link in &d.links {
+        match link.strip_prefix("did:") {
+            Some(l) => {
+                let (target, fragment) = l.split_once('#').unwrap_or((l, ""));
+                ret.push((target, fragment, "link"));
+            }
+            None => ret.push((link.as_str(), "", "url")),
+        }
+    }
+    for (key, values) in &d.meta {
+        for l in values.iter().filter_map(|v| v.strip_prefix("did:")) {
+            let (target, fragment) = l.split_once('#').unwrap_or((l, ""));
+            ret.push((target, fragment, key.as_str()));
+        }
+    }
+    ret
 }
 
 /// Create and init sqlite3 database if necessary
 fn init_db(db_path: &PathBuf) -> Result<Connection> {
     let conn = Connection::open(db_path)?;
+    conn.execute("PRAGMA foreign_keys = ON;")?;
+    migrate(&conn)?;
+    Ok(conn)
+}
 
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS definitions (
-            id INTEGER PRIMARY KEY,
-            term TEXT NOT NULL UNIQUE,
-            definition TEXT NOT NULL
-        )",
-    )?;
+/// Bring the schema up to date, one migration per version
+fn migrate(conn: &Connection) -> Result<()> {
+    conn.execute("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL);")?;
+    let mut stmt = conn.prepare("SELECT version FROM schema_version;")?;
+    let mut version = if let State::Row = stmt.next()? {
+        stmt.read::<i64, _>(0)? as usize
+    } else if !has_table(conn, "documents")? {
+        0
+    } else if has_column(conn, "documents", "title")? {
+        // databases from before there was a schema version
+        2
+    } else {
+        1
+    };
+    while version < MIGRATIONS.len() {
+        info!("Migrate database to schema version {}", version + 1);
+        conn.execute("BEGIN TRANSACTION;")?;
+        conn.execute(MIGRATIONS[version])?;
+        version += 1;
+        conn.execute(format!(
+            "DELETE FROM schema_version; INSERT INTO schema_version VALUES ({});",
+            version
+        ))?;
+        conn.execute("COMMIT;")?;
+    }
+    Ok(())
+}
 
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS documents (
-            id INTEGER PRIMARY KEY,
-            did TEXT NOT NULL UNIQUE,
-            raw TEXT NOT NULL
-        )",
-    )?;
+fn has_table(conn: &Connection, table: &str) -> Result<bool> {
+    let mut stmt =
+        conn.prepare("SELECT count(*) FROM sqlite_master WHERE type='table' AND name=?;")?;
+    stmt.bind((1, table))?;
+    stmt.next()?;
+    Ok(stmt.read::<i64, _>(0)? > 0)
+}
 
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS requirements (
-            id INTEGER PRIMARY KEY,
-            description TEXT NOT NULL,
-            status TEXT NOT NULL
-        )",
-    )?;
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare("SELECT count(*) FROM pragma_table_info(?) WHERE name=?;")?;
+    stmt.bind((1, table))?;
+    stmt.bind((2, column))?;
+    stmt.next()?;
+    Ok(stmt.read::<i64, _>(0)? > 0)
+}
 
-    Ok(conn)
+/// Schema changes, the index plus one is the resulting schema version
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema
+    "CREATE TABLE definitions (
+        id INTEGER PRIMARY KEY,
+        term TEXT NOT NULL UNIQUE,
+        definition TEXT NOT NULL
+    );
+    CREATE TABLE documents (
+        id INTEGER PRIMARY KEY,
+        did TEXT NOT NULL UNIQUE,
+        raw TEXT NOT NULL
+    );
+    CREATE TABLE requirements (
+        id INTEGER PRIMARY KEY,
+        description TEXT NOT NULL,
+        status TEXT NOT NULL
+    );",
+    // 2: the document graph
+    "ALTER TABLE documents ADD COLUMN title TEXT NOT NULL DEFAULT '';
+    ALTER TABLE documents ADD COLUMN status TEXT NOT NULL DEFAULT '';
+    ALTER TABLE documents ADD COLUMN type TEXT NOT NULL DEFAULT '';
+    ALTER TABLE documents ADD COLUMN path TEXT NOT NULL DEFAULT '';
+    ALTER TABLE documents ADD COLUMN url TEXT NOT NULL DEFAULT '';
+    ALTER TABLE documents ADD COLUMN html TEXT NOT NULL DEFAULT '';
+    CREATE TABLE tags (
+        doc_id INTEGER NOT NULL REFERENCES documents(id) ON DELETE CASCADE,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (doc_id, tag)
+    );
+    CREATE INDEX tags_tag ON tags (tag);
+    CREATE TABLE meta (
+        doc_id INTEGER NOT NULL REFERENCES documents(id) ON DELETE CASCADE,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL
+    );
+    CREATE INDEX meta_doc ON meta (doc_id);
+    CREATE INDEX meta_key ON meta (key, value);
+    -- targets are DIDs, which may not exist
+    CREATE TABLE links (
+        doc_id INTEGER NOT NULL REFERENCES documents(id) ON DELETE CASCADE,
+        target TEXT NOT NULL,
+        fragment TEXT NOT NULL,
+        type TEXT NOT NULL
+    );
+    CREATE INDEX links_doc ON links (doc_id);
+    CREATE INDEX links_target ON links (target);
+    CREATE TABLE includes (
+        doc_id INTEGER NOT NULL REFERENCES documents(id) ON DELETE CASCADE,
+        target TEXT NOT NULL,
+        position INTEGER NOT NULL
+    );
+    CREATE INDEX includes_doc ON includes (doc_id);
+    CREATE INDEX includes_target ON includes (target);
+    CREATE TABLE media (
+        doc_id INTEGER NOT NULL REFERENCES documents(id) ON DELETE CASCADE,
+        path TEXT NOT NULL
+    );
+    CREATE INDEX media_doc ON media (doc_id);
+    DROP TABLE requirements;
+    CREATE TABLE requirements (
+        doc_id INTEGER PRIMARY KEY REFERENCES documents(id) ON DELETE CASCADE,
+        description TEXT NOT NULL,
+        status TEXT NOT NULL
+    );",
+    // 3: change detection
+    "ALTER TABLE documents ADD COLUMN hash TEXT NOT NULL DEFAULT '';",
+    // 4: build history
+    "CREATE TABLE snapshots (
+        id INTEGER PRIMARY KEY,
+        timestamp TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        git_rev TEXT NOT NULL
+    );
+    CREATE TABLE snapshot_docs (
+        snapshot_id INTEGER NOT NULL REFERENCES snapshots(id) ON DELETE CASCADE,
+        did TEXT NOT NULL,
+        title TEXT NOT NULL,
+        status TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        PRIMARY KEY (snapshot_id, did)
+    );",
+    // 5: glossary terms with the defining document
+    "ALTER TABLE definitions ADD COLUMN did TEXT NOT NULL DEFAULT '';",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(conn: &Connection) -> i64 {
+        let mut stmt = conn.prepare("SELECT version FROM schema_version;").unwrap();
+        stmt.next().unwrap();
+        stmt.read(0).unwrap()
+    }
+
+    #[test]
+    fn migrate_new_database() {
+        let conn = Connection::open(":memory:").unwrap();
+        migrate(&conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len() as i64);
+        // nothing to do the second time
+        migrate(&conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len() as i64);
+    }
+
+    #[test]
+    fn migrate_baseline_database() {
+        // the schema before there were migrations
+        let conn = Connection::open(":memory:").unwrap();
+        conn.execute(
+            "CREATE TABLE definitions (
+                id INTEGER PRIMARY KEY,
+                term TEXT NOT NULL UNIQUE,
+                definition TEXT NOT NULL
+            );
+            CREATE TABLE documents (
+                id INTEGER PRIMARY KEY,
+                did TEXT NOT NULL UNIQUE,
+                raw TEXT NOT NULL
+            );
+            CREATE TABLE requirements (
+                id INTEGER PRIMARY KEY,
+                description TEXT NOT NULL,
+                status TEXT NOT NULL
+            );
+            INSERT INTO documents (did, raw) VALUES ('a', '# A');
+            INSERT INTO definitions (term, definition) VALUES ('DID', 'Document ID');",
+        )
+        .unwrap();
+        migrate(&conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len() as i64);
+        assert!(has_column(&conn, "documents", "title").unwrap());
+        assert!(has_column(&conn, "documents", "hash").unwrap());
+        assert!(has_column(&conn, "definitions", "did").unwrap());
+        assert!(has_column(&conn, "requirements", "doc_id").unwrap());
+        assert!(has_table(&conn, "snapshot_docs").unwrap());
+        let mut stmt = conn
+            .prepare("SELECT did, raw, title, hash FROM documents;")
+            .unwrap();
+        stmt.next().unwrap();
+        let row: Vec<String> = (0..4).map(|i| stmt.read(i).unwrap()).collect();
+        assert_eq!(row, ["a", "# A", "", ""]);
+    }
+
+    #[test]
+    fn migrate_unversioned_database() {
+        // schema version 2 from before the schema_version table
+        let conn = Connection::open(":memory:").unwrap();
+        conn.execute(MIGRATIONS[0]).unwrap();
+        conn.execute(MIGRATIONS[1]).unwrap();
+        migrate(&conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len() as i64);
+        assert!(has_table(&conn, "snapshots").unwrap());
+    }
 }
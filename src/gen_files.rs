@@ -1,12 +1,15 @@
-use log::{info, warn};
-use serde_json;
+use log::{error, info, warn};
+use pulldown_cmark_escape::escape_html;
 use std::fs::{self, create_dir_all, File};
 use std::io::prelude::*;
 use std::io::BufWriter;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::config::Config;
-use crate::gen_html::{read_md_files, Doc};
+use crate::gen_html::{mark_links_to, read_md_files, status_badge, Doc};
+use crate::glossary;
+use crate::ids;
+use crate::status;
 
 pub(crate) fn cmd_gen(cfg: &Config) {
     let output = cfg.output.clone();
@@ -16,19 +19,52 @@ pub(crate) fn cmd_gen(cfg: &Config) {
     info!("output: {}", &output.display());
     let mut docs: Vec<Doc> = vec![];
     for src in cfg.get_sources() {
-        read_md_files(&mut docs, src.as_path());
+        read_md_files(&mut docs, src.as_path(), cfg);
+    }
+    ids::check_hash_dids(&docs);
+    status::check_lifecycles(&docs, cfg);
+    status::check_released_links(&docs, cfg);
+    status::check_inactive_links(&docs, cfg);
+    // with the excluded documents, so their status changes are not lost
+    status::write_snapshot(&output, &docs).unwrap();
+    let excluded: Vec<String> = docs
+        .iter()
+        .filter(|d| cfg.is_excluded(&d.status))
+        .map(|d| d.did.clone())
+        .collect();
+    docs.retain(|d| !cfg.is_excluded(&d.status));
+    for d in &mut docs {
+        for did in &excluded {
+            d.html = mark_links_to(&d.html, did, "inactive-excluded");
+        }
+    }
+    let terms = glossary::collect(&docs);
+    if !terms.is_empty() {
+        glossary::link_terms(&mut docs, &terms);
+        if docs.iter().any(|d| d.did == glossary::GLOSSARY_DID) {
+            warn!(
+                "No glossary page, the DID is taken: {}",
+                glossary::GLOSSARY_DID
+            );
+        } else {
+            docs.push(glossary::glossary_doc(&terms));
+        }
     }
     let template: Vec<&str> = TMPL.split(&"XXX").collect();
     let inverse_output = invert_path(&output);
     for d in &docs {
-        let output_file_path = output.join(&d.html_path());
-        write_html_doc(&output_file_path, &template, &"../", &d).expect("write html");
+        let output_file_path = output.join(d.html_path());
+        write_html_doc(&output_file_path, &template, "../", d, cfg).expect("write html");
         for media in &d.media {
             let tgt = PathBuf::from("..").join(&inverse_output).join(media);
             let symlink = output_file_path
                 .parent()
                 .unwrap()
-                .join(&media.file_name().unwrap());
+                .join(media.file_name().unwrap());
+            if symlink.symlink_metadata().is_ok() {
+                // from a previous run
+                continue;
+            }
             std::os::unix::fs::symlink(tgt, symlink).unwrap();
         }
     }
@@ -36,11 +72,16 @@ pub(crate) fn cmd_gen(cfg: &Config) {
     write_index_file(&output, &template, &docs, cfg).unwrap();
     write_static_files(&output, &cfg.theme).unwrap();
     write_globals_file(&output, &docs).unwrap();
+    let undefined = docs.iter().filter(|d| !d.undefined_vars.is_empty()).count();
+    if undefined > 0 {
+        error!("{} documents use undefined variables", undefined);
+        std::process::exit(1);
+    }
 }
 
 // Inverts a path, assuming it is relative to the current dir
 // Example: ../foo becomes ../src if your are currently in src/
-fn invert_path(output: &PathBuf) -> PathBuf {
+fn invert_path(output: &Path) -> PathBuf {
     let mut ret = PathBuf::new();
     let mut cur = PathBuf::from(".").canonicalize().unwrap();
     for c in output.components() {
@@ -48,13 +89,13 @@ fn invert_path(output: &PathBuf) -> PathBuf {
             ret = ret.join(cur.file_name().unwrap());
             cur = cur.parent().unwrap().to_path_buf();
         } else {
-            ret = ret.join(&"..");
+            ret = ret.join("..");
         }
     }
     ret
 }
 
-fn write_globals_file(output_dir: &PathBuf, docs: &Vec<Doc>) -> Result<(), std::io::Error> {
+fn write_globals_file(output_dir: &Path, docs: &[Doc]) -> Result<(), std::io::Error> {
     let mut c = String::new();
     c.push_str("const DOCDUSTRY_GLOBALS = {");
 
@@ -72,7 +113,7 @@ fn write_globals_file(output_dir: &PathBuf, docs: &Vec<Doc>) -> Result<(), std::
     Ok(())
 }
 
-fn write_static_files(output_dir: &PathBuf, theme: &Option<PathBuf>) -> Result<(), std::io::Error> {
+fn write_static_files(output_dir: &Path, theme: &Option<PathBuf>) -> Result<(), std::io::Error> {
     let dir = output_dir.join("docdustry_static");
     create_dir_all(&dir)?;
     fs::write(dir.join("base.css"), CSS_BASE)?;
@@ -84,7 +125,7 @@ fn write_static_files(output_dir: &PathBuf, theme: &Option<PathBuf>) -> Result<(
 
 fn theme_css_content(theme: &Option<PathBuf>) -> Vec<u8> {
     match theme {
-        Some(p) => match std::fs::read(&p) {
+        Some(p) => match std::fs::read(p) {
             Ok(x) => x,
             Err(_) => {
                 warn!("Failed to read theme css: {}", p.display());
@@ -97,16 +138,16 @@ fn theme_css_content(theme: &Option<PathBuf>) -> Vec<u8> {
 
 fn write_index_file(
     output_dir: &PathBuf,
-    template: &Vec<&str>,
-    docs: &Vec<Doc>,
+    template: &[&str],
+    docs: &[Doc],
     cfg: &Config,
 ) -> Result<(), std::io::Error> {
-    let output_file_path = output_dir.join(&"index.html");
+    let output_file_path = output_dir.join("index.html");
     if let Some(did) = cfg.frontpage.clone() {
         match docs.iter().find(|d| d.did == did) {
             Some(d) => {
                 info!("output {}", &output_file_path.display());
-                write_html_doc(&output_file_path, template, &"", d).unwrap();
+                write_html_doc(&output_file_path, template, "", d, cfg).unwrap();
                 return Ok(());
             }
             None => {
@@ -116,37 +157,51 @@ fn write_index_file(
     };
     let mut doc = Doc::new(PathBuf::from(output_dir), PathBuf::from("index.html"));
     doc.html = "<p>Please search!</p>".to_string();
-    write_html_doc(&output_file_path, template, &"", &doc).unwrap();
+    write_html_doc(&output_file_path, template, "", &doc, cfg).unwrap();
     Ok(())
 }
 
 fn write_html_doc(
     output_file_path: &PathBuf,
-    template: &Vec<&str>,
+    template: &[&str],
     path_prefix: &str,
     d: &Doc,
+    cfg: &Config,
 ) -> Result<(), std::io::Error> {
-    create_dir_all(&output_file_path.parent().unwrap())?;
+    create_dir_all(output_file_path.parent().unwrap())?;
     let title = &d.title;
     let content = &d.html;
+    let toc = &d.toc;
     let json: &str = &serde_json::to_string(&d)?;
-    let fh = File::create(&output_file_path)?;
+    let fh = File::create(output_file_path)?;
     let mut st = BufWriter::new(fh);
-    st.write(template[0].as_bytes())?;
-    st.write(title.as_bytes())?;
-    st.write(template[1].as_bytes())?;
-    st.write(path_prefix.as_bytes())?;
-    st.write(template[2].as_bytes())?;
-    st.write(path_prefix.as_bytes())?;
-    st.write(template[3].as_bytes())?;
-    st.write(path_prefix.as_bytes())?;
-    st.write(template[4].as_bytes())?;
-    st.write(path_prefix.as_bytes())?;
-    st.write(template[5].as_bytes())?;
-    st.write(json.as_bytes())?;
-    st.write(template[6].as_bytes())?;
-    st.write(content.as_bytes())?;
-    st.write(template[7].as_bytes())?;
+    st.write_all(template[0].as_bytes())?;
+    st.write_all(title.as_bytes())?;
+    st.write_all(template[1].as_bytes())?;
+    st.write_all(path_prefix.as_bytes())?;
+    st.write_all(template[2].as_bytes())?;
+    st.write_all(path_prefix.as_bytes())?;
+    st.write_all(template[3].as_bytes())?;
+    st.write_all(path_prefix.as_bytes())?;
+    st.write_all(template[4].as_bytes())?;
+    st.write_all(path_prefix.as_bytes())?;
+    st.write_all(template[5].as_bytes())?;
+    st.write_all(json.as_bytes())?;
+    st.write_all(template[6].as_bytes())?;
+    st.write_all(toc.as_bytes())?;
+    st.write_all(template[7].as_bytes())?;
+    if !d.status.is_empty() {
+        st.write_all(status_badge(&d.status).as_bytes())?;
+    }
+    if cfg.is_inactive(&d.status) {
+        st.write_all(r#"<div class="inactive-banner">This document is "#.as_bytes())?;
+        let mut status = String::new();
+        escape_html(&mut status, &d.status).unwrap();
+        st.write_all(status.as_bytes())?;
+        st.write_all(b".</div>")?;
+    }
+    st.write_all(content.as_bytes())?;
+    st.write_all(template[8].as_bytes())?;
     Ok(())
 }
 
@@ -161,10 +216,10 @@ const TMPL: &str = r#"<!DOCTYPE html>
 <script src="XXXdocdustry_static/globals.js" type="text/javascript" defer></script>
 <script type="text/javascript">const DOCDUSTRY_LOCALS = XXX;</script>
 <body><header></header>
-<div class="page">
+<div class="page">XXX
 <section class="main">XXX</section>
 </div>
 <footer></footer></body></html>"#;
-const CSS_BASE: &'static [u8] = include_bytes!("base.css");
-const CSS_THEME: &'static [u8] = include_bytes!("theme.css");
-const JS: &'static [u8] = include_bytes!("default.js");
+const CSS_BASE: &[u8] = include_bytes!("base.css");
+const CSS_THEME: &[u8] = include_bytes!("theme.css");
+const JS: &[u8] = include_bytes!("default.js");
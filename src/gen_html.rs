@@ -1,24 +1,38 @@
 use ignore::Walk;
 use log::{error, info, warn};
-use pulldown_cmark::Parser;
 use pulldown_cmark::{CodeBlockKind, CowStr, Event, HeadingLevel, Tag, TagEnd};
+use pulldown_cmark::{Options, Parser};
 use pulldown_cmark_escape::escape_html;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fs::read_to_string;
 use std::io::{self};
 use std::path::{Path, PathBuf};
 
+use crate::conditions;
+use crate::config::Config;
+use crate::headings;
+use crate::highlight;
+use crate::query::{Expr, Fields, Selection};
+use crate::vars;
+
 #[derive(serde::Serialize)]
 pub struct Doc {
     /// document ID, the unique identifier for linking to it
     pub did: String,
     pub title: String,
     pub status: String,
+    /// document type like `requirement`, selecting the lifecycle
+    #[serde(rename = "type")]
+    pub doc_type: String,
     pub links: Vec<String>,
     pub tags: Vec<String>,
+    /// other fields of the doc meta info
+    pub meta: BTreeMap<String, Vec<String>>,
     pub url: String,
     // other pages getting included via DID
     pub includes: Vec<String>,
+    /// documents listed by doclists, for their backlinks
+    pub listed: Vec<String>,
 
     #[serde(skip)]
     pub media: Vec<PathBuf>,
@@ -26,12 +40,43 @@ pub struct Doc {
     pub raw: String,
     #[serde(skip)]
     pub html: String,
+    /// table of contents for the side of the page
+    #[serde(skip)]
+    pub toc: String,
+    #[serde(skip)]
+    toc_enabled: bool,
+    #[serde(skip)]
+    toc_depth: usize,
     #[serde(skip)]
     pub src_path_rel: PathBuf,
     #[serde(skip)]
     src_path_base: PathBuf,
     #[serde(skip)]
     redo: bool,
+    #[serde(skip)]
+    in_paragraph: bool,
+    /// doclists waiting for the metadata of all documents
+    #[serde(skip)]
+    doclists: Vec<DocList>,
+    #[serde(skip)]
+    heading: Option<HeadingState>,
+    /// heading ids used so far
+    #[serde(skip)]
+    slugs: HashSet<String>,
+    /// terms and definitions of glossary blocks
+    #[serde(skip)]
+    pub glossary: Vec<(String, String)>,
+    /// names of `{{var}}` without a value
+    #[serde(skip)]
+    pub undefined_vars: Vec<String>,
+}
+
+/// The heading currently being generated
+struct HeadingState {
+    /// where to insert the id attribute
+    id_pos: usize,
+    id: Option<String>,
+    text: String,
 }
 
 impl Doc {
@@ -40,38 +85,73 @@ impl Doc {
             src_path_rel,
             src_path_base,
             html: String::with_capacity(4000),
+            toc: String::new(),
+            toc_enabled: true,
+            toc_depth: 6,
             title: String::new(),
             links: vec![],
             tags: vec![],
+            meta: BTreeMap::new(),
             did: String::new(),
             status: String::new(),
+            doc_type: String::new(),
             url: String::new(),
             includes: vec![],
+            listed: vec![],
             raw: String::new(),
             redo: false,
+            in_paragraph: false,
+            doclists: vec![],
+            heading: None,
+            slugs: HashSet::new(),
+            glossary: vec![],
+            undefined_vars: vec![],
             media: vec![],
         }
     }
 
-    fn gen_html(&mut self) -> Result<(), io::Error> {
-        let file = self.src_path_base.join(&self.src_path_rel);
-        let raw = read_to_string(file)?;
-        self.parse_md(&raw, &None, &vec![]);
+    fn gen_html(&mut self, cfg: &Config) -> Result<(), io::Error> {
+        let raw = read_to_string(self.src_path())?;
+        self.parse_md(&raw, &None, &[], cfg);
         if self.redo {
             self.html.clear();
-            self.raw = raw;
-            self.redo = false;
+            self.media.clear();
         }
+        self.raw = raw;
         Ok(())
     }
 
     fn parse_md(
         &mut self,
-        raw: &String,
+        raw: &str,
         include_map: &Option<HashMap<String, String>>,
-        metas: &Vec<DocMeta>,
+        metas: &[DocMeta],
+        cfg: &Config,
     ) {
-        let mut parser = Parser::new(raw);
+        self.slugs.clear();
+        // collected again
+        self.links.clear();
+        self.tags.clear();
+        self.meta.clear();
+        self.glossary.clear();
+        self.undefined_vars.clear();
+        self.listed.clear();
+        let doc_vars = vars::doc_vars(raw);
+        // for messages, the DID is not known before the doc meta info
+        let name = match doc_vars.get("id") {
+            Some(id) => id.clone(),
+            None => self.src_path_rel.display().to_string(),
+        };
+        let raw = conditions::apply(
+            raw,
+            &conditions::Context {
+                cfg,
+                doc_vars: &doc_vars,
+            },
+            &name,
+        );
+        let raw = vars::substitute(&raw, &doc_vars, cfg, &name, &mut self.undefined_vars);
+        let mut parser = Parser::new_ext(&raw, Options::ENABLE_HEADING_ATTRIBUTES);
         while let Some(event) = parser.next() {
             match event {
                 Event::Start(tag) => match tag {
@@ -83,24 +163,23 @@ impl Doc {
                     } => {
                         self.html.push('<');
                         self.html.push_str(&level.to_string());
-                        if let Some(s) = id {
-                            self.html.push_str(&" id=\"");
-                            self.html.push_str(&s);
+                        if let Some(s) = &id {
+                            self.html.push_str(" id=\"");
+                            self.html.push_str(s);
                             self.html.push('"');
                         }
+                        self.heading = Some(HeadingState {
+                            id_pos: self.html.len(),
+                            id: id.map(|s| s.to_string()),
+                            text: String::new(),
+                        });
                         self.html.push('>');
-                        if level == HeadingLevel::H1 {
-                            if let Some(Event::Text(t)) = parser.next() {
-                                self.html.push_str(&t);
-                                if self.title.is_empty() {
-                                    self.title = t.to_string();
-                                }
-                            }
-                        }
                     }
                     Tag::CodeBlock(kind) => match kind {
-                        CodeBlockKind::Indented => self.html.push_str(&"<pre><code>"),
-                        CodeBlockKind::Fenced(lang) => self.gen_codeblock(lang, &mut parser, metas),
+                        CodeBlockKind::Indented => self.html.push_str("<pre><code>"),
+                        CodeBlockKind::Fenced(lang) => {
+                            self.gen_codeblock(lang, &mut parser, include_map, metas)
+                        }
                     },
                     Tag::Link {
                         link_type: _,
@@ -108,84 +187,103 @@ impl Doc {
                         title,
                         id,
                     } => self.gen_link(dest_url, id, title),
-                    Tag::Paragraph => self.html.push_str(&"<p>"),
-                    Tag::BlockQuote => self.html.push_str(&"<blockquote>"),
-                    Tag::HtmlBlock => self.html.push_str(&"<div html>"),
+                    Tag::Paragraph => {
+                        self.html.push_str("<p>");
+                        self.in_paragraph = true;
+                    }
+                    Tag::BlockQuote => self.html.push_str("<blockquote>"),
+                    Tag::HtmlBlock => self.html.push_str("<div html>"),
                     Tag::List(first) => match first {
                         Some(start_num) => {
-                            self.html.push_str(&"<ol");
+                            self.html.push_str("<ol");
                             if start_num != 1 {
                                 self.html.push_str(&format!(r#" start="{}""#, start_num));
                             }
                             self.html.push('>');
                         }
-                        None => self.html.push_str(&"<ul>"),
+                        None => self.html.push_str("<ul>"),
                     },
-                    Tag::Item => self.html.push_str(&"<li>"),
+                    Tag::Item => self.html.push_str("<li>"),
                     Tag::FootnoteDefinition(_) => todo!(),
                     Tag::Table(_) => todo!(),
-                    Tag::TableHead => self.html.push_str(&"<th>"),
-                    Tag::TableRow => self.html.push_str(&"<tr>"),
-                    Tag::TableCell => self.html.push_str(&"<td>"),
-                    Tag::Emphasis => self.html.push_str(&"<em>"),
-                    Tag::Strong => self.html.push_str(&"<strong>"),
-                    Tag::Strikethrough => self.html.push_str(&"<del>"),
+                    Tag::TableHead => self.html.push_str("<th>"),
+                    Tag::TableRow => self.html.push_str("<tr>"),
+                    Tag::TableCell => self.html.push_str("<td>"),
+                    Tag::Emphasis => self.html.push_str("<em>"),
+                    Tag::Strong => self.html.push_str("<strong>"),
+                    Tag::Strikethrough => self.html.push_str("<del>"),
                     Tag::Image {
                         link_type: _,
                         dest_url,
                         title,
                         id,
-                    } => self.gen_img(dest_url, include_map, &mut parser, id, title),
+                    } => self.gen_img(dest_url, include_map, &mut parser, id, title, cfg),
                     Tag::MetadataBlock(_) => todo!(),
                 },
                 Event::End(tag) => match tag {
-                    TagEnd::Paragraph => self.html.push_str(&"</p>"),
-                    TagEnd::Heading(level) => self.html.push_str(&format!("</{}>", level)),
-                    TagEnd::BlockQuote => self.html.push_str(&"</blockquote>"),
-                    TagEnd::CodeBlock => self.html.push_str(&"</pre></code>"),
-                    TagEnd::HtmlBlock => self.html.push_str(&"</div>"),
+                    TagEnd::Paragraph => {
+                        // the paragraph might be empty after a figure
+                        if self.html.ends_with("<p>") {
+                            self.html.truncate(self.html.len() - 3);
+                        } else {
+                            self.html.push_str("</p>");
+                        }
+                        self.in_paragraph = false;
+                    }
+                    TagEnd::Heading(level) => self.gen_heading_end(level),
+                    TagEnd::BlockQuote => self.html.push_str("</blockquote>"),
+                    TagEnd::CodeBlock => self.html.push_str("</code></pre>"),
+                    TagEnd::HtmlBlock => self.html.push_str("</div>"),
                     TagEnd::List(ordered) => match ordered {
-                        true => self.html.push_str(&"</ol>"),
-                        false => self.html.push_str(&"</ul>"),
+                        true => self.html.push_str("</ol>"),
+                        false => self.html.push_str("</ul>"),
                     },
-                    TagEnd::Item => self.html.push_str(&"</li>"),
+                    TagEnd::Item => self.html.push_str("</li>"),
                     TagEnd::FootnoteDefinition => todo!(),
-                    TagEnd::Table => self.html.push_str(&"</table>"),
-                    TagEnd::TableHead => self.html.push_str(&"</th>"),
-                    TagEnd::TableRow => self.html.push_str(&"</tr>"),
-                    TagEnd::TableCell => self.html.push_str(&"</td>"),
-                    TagEnd::Emphasis => self.html.push_str(&"</em>"),
-                    TagEnd::Strong => self.html.push_str(&"</strong>"),
-                    TagEnd::Strikethrough => self.html.push_str(&"</del>"),
-                    TagEnd::Link => self.html.push_str(&"</a>"),
-                    TagEnd::Image => self.html.push_str(&"</img>"),
+                    TagEnd::Table => self.html.push_str("</table>"),
+                    TagEnd::TableHead => self.html.push_str("</th>"),
+                    TagEnd::TableRow => self.html.push_str("</tr>"),
+                    TagEnd::TableCell => self.html.push_str("</td>"),
+                    TagEnd::Emphasis => self.html.push_str("</em>"),
+                    TagEnd::Strong => self.html.push_str("</strong>"),
+                    TagEnd::Strikethrough => self.html.push_str("</del>"),
+                    TagEnd::Link => self.html.push_str("</a>"),
+                    TagEnd::Image => self.html.push_str("</img>"),
                     TagEnd::MetadataBlock(_) => todo!(),
                 },
-                Event::Text(t) => escape_html(&mut self.html, &t).unwrap(),
+                Event::Text(t) => {
+                    if let Some(h) = &mut self.heading {
+                        h.text.push_str(&t);
+                    }
+                    escape_html(&mut self.html, &t).unwrap();
+                }
                 Event::Code(c) => {
-                    self.html.push_str(&"<code>");
+                    if let Some(h) = &mut self.heading {
+                        h.text.push_str(&c);
+                    }
+                    self.html.push_str("<code>");
                     escape_html(&mut self.html, &c).unwrap();
-                    self.html.push_str(&"</code>");
+                    self.html.push_str("</code>");
                 }
                 Event::Html(t) => self.html.push_str(&t),
                 Event::InlineHtml(t) => self.html.push_str(&t),
                 Event::FootnoteReference(_) => todo!(),
                 Event::SoftBreak => self.html.push('\n'),
-                Event::HardBreak => self.html.push_str(&"<br/>"),
-                Event::Rule => self.html.push_str(&"<hr/>"),
+                Event::HardBreak => self.html.push_str("<br/>"),
+                Event::Rule => self.html.push_str("<hr/>"),
                 Event::TaskListMarker(_) => todo!(),
             }
         }
         // post-processing
         if self.did.is_empty() {
             let mut ctx = md5::Context::new();
-            ctx.consume(&self.src_path_rel.as_os_str().as_encoded_bytes());
+            ctx.consume(self.src_path_rel.as_os_str().as_encoded_bytes());
             ctx.consume(&self.title);
             let hash = ctx.compute();
             self.did = format!("{:x}", hash);
         }
         if self.title.is_empty() {
-            self.title.push_str(&"<unknown>");
+            self.title.push_str("<unknown>");
         }
         self.url = self.rel_url();
     }
@@ -197,108 +295,487 @@ impl Doc {
         parser: &mut Parser<'_>,
         id: CowStr<'_>,
         title: CowStr<'_>,
+        cfg: &Config,
     ) {
-        if dest_url.starts_with(&"did:") {
+        if let Some(target) = dest_url.strip_prefix("did:") {
             // include another page
-            let did = dest_url[4..].to_string();
+            let inc = Inclusion::parse(target);
             match include_map {
                 Some(m) => {
-                    match m.get(&did) {
-                        Some(html) => {
-                            self.html.push_str(&r#"<article class="inclusion">"#);
-                            self.html.push_str(&r#"<a class="inclusion" href=""#);
-                            self.html.push_str(&dest_url);
-                            self.html.push_str(&"\">inclusion</a>\n");
-                            self.html.push_str(html);
-                            self.html.push_str(&"</article>\n");
-                        }
-                        None => {
-                            warn!("Including non-existant DID: {}", dest_url);
-                            self.html.push_str(&r#"<p class="error">Inclusion fail: "#);
-                            self.html.push_str(&dest_url);
-                            self.html.push_str(&"</p>\n");
-                        }
-                    };
-                    skip_img_rest(parser);
+                    img_alt_text(parser);
+                    self.gen_inclusion(&inc, m);
                 }
                 None => {
-                    self.includes.push(did);
+                    self.includes.push(inc.did);
                     self.redo = true;
                 }
             };
+        } else if !title.is_empty() {
+            self.gen_figure(dest_url, parser, title, cfg);
         } else {
             // normal image
-            self.html.push_str(&"<img");
-            if !dest_url.is_empty() {
-                let as_path = PathBuf::from(dest_url.to_string());
-                let filename = as_path.file_name();
-                self.html.push_str(" src=\"");
-                self.html.push_str(&filename.unwrap().to_str().unwrap());
-                self.html.push('"');
-                let path = self
-                    .src_path_base
-                    .join(&self.src_path_rel.parent().unwrap())
-                    .join(&dest_url.to_string());
-                self.media.push(path);
-            }
-            if !id.is_empty() {
-                self.html.push_str(" id=\"");
-                self.html.push_str(&id);
-                self.html.push('"');
+            let alt = img_alt_text(parser);
+            self.gen_img_tag(&dest_url, &id, &alt, cfg);
+        }
+    }
+
+    /// Insert the HTML of another document
+    fn gen_inclusion(&mut self, inc: &Inclusion, include_map: &HashMap<String, String>) {
+        let html = match include_map.get(&inc.did) {
+            Some(html) => html,
+            None => {
+                warn!("Including non-existant DID: {}", inc.did);
+                self.gen_inclusion_error(&inc.did);
+                return;
             }
-            if !title.is_empty() {
-                self.html.push_str(" title=\"");
-                self.html.push_str(&title);
-                self.html.push('"');
+        };
+        let html = match &inc.section {
+            Some(section) => match headings::extract_section(html, section) {
+                Some(s) => s,
+                None => {
+                    warn!("Including non-existant section: {}#{}", inc.did, section);
+                    self.gen_inclusion_error(&format!("{}#{}", inc.did, section));
+                    return;
+                }
+            },
+            None => html,
+        };
+        let html = if inc.notitle {
+            headings::strip_title(html)
+        } else {
+            html.to_string()
+        };
+        // an included document is one level below the host,
+        // so its H1 becomes H2 and so on
+        let top = headings::first_heading_level(&html).unwrap_or(1);
+        let html = headings::shift_headings(&html, 2 - top as i32);
+        self.leave_paragraph();
+        self.html.push_str(r#"<article class="inclusion">"#);
+        self.html.push_str(r#"<a class="inclusion" href="did:"#);
+        self.html.push_str(&inc.did);
+        if let Some(section) = &inc.section {
+            self.html.push('#');
+            self.html.push_str(section);
+        }
+        self.html.push_str("\">inclusion</a>\n");
+        self.html.push_str(&html);
+        self.html.push_str("</article>\n");
+        self.reenter_paragraph();
+    }
+
+    /// Give the heading an id, if not explicit, and a link to itself
+    fn gen_heading_end(&mut self, level: HeadingLevel) {
+        if let Some(h) = self.heading.take() {
+            let id = match h.id {
+                Some(id) => {
+                    self.slugs.insert(id.clone());
+                    id
+                }
+                None => {
+                    let id = self.unique_slug(&h.text);
+                    self.html.insert_str(h.id_pos, &format!(" id=\"{}\"", id));
+                    id
+                }
+            };
+            self.html.push_str(r##"<a class="heading-anchor" href="#"##);
+            self.html.push_str(&id);
+            self.html.push_str("\"></a>");
+            if level == HeadingLevel::H1 && self.title.is_empty() {
+                self.title = h.text;
             }
-            self.html.push('>');
-            skip_img_rest(parser);
         }
+        self.html.push_str(&format!("</{}>", level));
+    }
+
+    /// A heading id derived from the text, which is not used yet in this document
+    fn unique_slug(&mut self, text: &str) -> String {
+        let slug = headings::slugify(text);
+        let mut id = slug.clone();
+        let mut n = 1;
+        while self.slugs.contains(&id) {
+            id = format!("{}-{}", slug, n);
+            n += 1;
+        }
+        self.slugs.insert(id.clone());
+        id
+    }
+
+    fn gen_inclusion_error(&mut self, target: &str) {
+        self.leave_paragraph();
+        self.html
+            .push_str(r#"<p class="error">Inclusion fail: did:"#);
+        self.html.push_str(target);
+        self.html.push_str("</p>\n");
+        self.reenter_paragraph();
+    }
+
+    /// Close the current paragraph before a block element like a figure
+    fn leave_paragraph(&mut self) {
+        if self.in_paragraph {
+            if self.html.ends_with("<p>") {
+                self.html.truncate(self.html.len() - 3);
+            } else {
+                self.html.push_str("</p>");
+            }
+        }
+    }
+
+    /// Continue a paragraph after leave_paragraph
+    fn reenter_paragraph(&mut self) {
+        if self.in_paragraph {
+            self.html.push_str("<p>");
+        }
+    }
+
+    /// An image with a title becomes a numbered figure with a caption.
+    /// The number is filled in by number_figures once composition is done.
+    fn gen_figure(
+        &mut self,
+        dest_url: CowStr<'_>,
+        parser: &mut Parser<'_>,
+        title: CowStr<'_>,
+        cfg: &Config,
+    ) {
+        let alt = img_alt_text(parser);
+        let fig_id = figure_id(&dest_url);
+        // a figure is not allowed within a paragraph
+        self.leave_paragraph();
+        self.html.push_str("<figure id=\"");
+        self.html.push_str(&fig_id);
+        self.html.push_str("\">");
+        self.gen_img_tag(&dest_url, "", &alt, cfg);
+        self.html
+            .push_str("<figcaption><span class=\"fignum\" data-ref=\"");
+        self.html.push_str(&fig_id);
+        self.html.push_str("\"></span> ");
+        escape_html(&mut self.html, &title).unwrap();
+        self.html.push_str("</figcaption></figure>\n");
+        self.reenter_paragraph();
+    }
+
+    fn gen_img_tag(&mut self, dest_url: &str, id: &str, alt: &str, cfg: &Config) {
+        if cfg.inline_svg && dest_url.ends_with(".svg") && self.gen_inline_svg(dest_url) {
+            return;
+        }
+        self.html.push_str("<img");
+        if !dest_url.is_empty() {
+            let as_path = PathBuf::from(dest_url.to_string());
+            let filename = as_path.file_name();
+            self.html.push_str(" src=\"");
+            self.html.push_str(filename.unwrap().to_str().unwrap());
+            self.html.push('"');
+            let path = self
+                .src_path_base
+                .join(self.src_path_rel.parent().unwrap())
+                .join(dest_url);
+            self.media.push(path);
+        }
+        if !id.is_empty() {
+            self.html.push_str(" id=\"");
+            self.html.push_str(id);
+            self.html.push('"');
+        }
+        if !alt.is_empty() {
+            self.html.push_str(" alt=\"");
+            escape_html(&mut self.html, alt).unwrap();
+            self.html.push('"');
+        }
+        self.html.push('>');
+    }
+
+    /// Copy an SVG file into the HTML instead of referencing it
+    fn gen_inline_svg(&mut self, dest_url: &str) -> bool {
+        let path = self
+            .src_path_base
+            .join(self.src_path_rel.parent().unwrap())
+            .join(dest_url);
+        let svg = match read_to_string(&path) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Cannot inline {}: {}", path.display(), e);
+                return false;
+            }
+        };
+        // skip xml declaration, doctype, and comments before the root element
+        match svg.find("<svg") {
+            Some(start) => {
+                self.html.push_str(svg[start..].trim_end());
+                true
+            }
+            None => {
+                warn!("Not an SVG file: {}", path.display());
+                false
+            }
+        }
+    }
+
+    /// Post-processing once all inclusions are done
+    fn finish_html(&mut self) {
+        self.number_captions("fig", "Figure");
+        self.number_captions("listing", "Listing");
+        self.html = headings::dedup_ids(&self.html);
+        let outline = headings::outline(&self.html);
+        if self.toc_enabled {
+            self.toc = headings::toc(&outline, self.toc_depth);
+        }
+        const TOC_BLOCK: &str = r#"<nav class="docdustry-toc" data-depth=""#;
+        let mut pos = 0;
+        while let Some(i) = self.html[pos..].find(TOC_BLOCK) {
+            let start = pos + i;
+            let after = start + TOC_BLOCK.len();
+            let (len, end) = match (
+                self.html[after..].find('"'),
+                self.html[after..].find("</nav>"),
+            ) {
+                (Some(len), Some(end)) if len < end => (len, after + end + 6),
+                // like the placeholder, but from raw HTML
+                _ => {
+                    pos = after;
+                    continue;
+                }
+            };
+            let depth = self.html[after..after + len].parse().unwrap_or(6);
+            let toc = format!(
+                r#"<nav class="docdustry-toc-block">{}</nav>"#,
+                headings::toc_list(&outline, depth)
+            );
+            self.html.replace_range(start..end, &toc);
+            pos = start + toc.len();
+        }
+    }
+
+    /// Replace placeholders of figures or listings with numbers in order
+    /// of appearance and resolve fig: or listing: references to them.
+    /// Repeated ids get a number appended, references go to the first one.
+    fn number_captions(&mut self, kind: &str, label: &str) {
+        let num = format!(r#"<span class="{}num" data-ref=""#, kind);
+        let mut numbers: HashMap<String, usize> = HashMap::new();
+        let mut html = String::with_capacity(self.html.len());
+        let mut rest = self.html.as_str();
+        let mut n = 0;
+        while let Some(start) = rest.find(&num) {
+            let after = &rest[start + num.len()..];
+            html.push_str(&rest[..start + num.len()]);
+            let end = match after.find('"') {
+                Some(end) if after[end..].starts_with("\">") => end,
+                // like the placeholder, but from raw HTML
+                _ => {
+                    rest = after;
+                    continue;
+                }
+            };
+            let ref_id = &after[..end];
+            n += 1;
+            if numbers.contains_key(ref_id) {
+                // the same image again or another one with the same file name
+                let mut k = 1;
+                let mut new_id = format!("{}-{}", ref_id, k);
+                while self.html.contains(&format!(" id=\"{}\"", new_id))
+                    || html.contains(&format!(" id=\"{}\"", new_id))
+                {
+                    k += 1;
+                    new_id = format!("{}-{}", ref_id, k);
+                }
+                let attr = format!(" id=\"{}\"", ref_id);
+                if let Some(p) = html.rfind(&attr) {
+                    html.replace_range(p..p + attr.len(), &format!(" id=\"{}\"", new_id));
+                }
+                html.push_str(&new_id);
+            } else {
+                numbers.insert(ref_id.to_string(), n);
+                html.push_str(ref_id);
+            }
+            html.push_str(&format!("\">{} {}:", label, n));
+            rest = &after[end + 2..];
+        }
+        html.push_str(rest);
+
+        let reference = format!(r##"<a class="{}ref" href="#"##, kind);
+        let mut out = String::with_capacity(html.len());
+        let mut rest = html.as_str();
+        while let Some(start) = rest.find(&reference) {
+            let after = &rest[start + reference.len()..];
+            let end = match after.find('"') {
+                Some(end) if after[end..].starts_with("\">") => end,
+                _ => {
+                    out.push_str(&rest[..start + reference.len()]);
+                    rest = after;
+                    continue;
+                }
+            };
+            let ref_id = &after[..end];
+            out.push_str(&rest[..start + reference.len() + end + 2]);
+            rest = &after[end + 2..];
+            if !rest.starts_with("</a>") {
+                continue;
+            }
+            match numbers.get(ref_id) {
+                Some(n) => out.push_str(&format!("{} {}", label, n)),
+                None => {
+                    warn!("Reference to unknown {} {} in {}", kind, ref_id, self.did);
+                    out.push_str(ref_id);
+                }
+            }
+        }
+        out.push_str(rest);
+        self.html = out;
     }
 
     fn gen_link(&mut self, dest_url: CowStr<'_>, id: CowStr<'_>, title: CowStr<'_>) {
-        self.html.push_str(&"<a href=\"");
+        if let Some(name) = dest_url.strip_prefix("fig:") {
+            self.html.push_str(r##"<a class="figref" href="#"##);
+            self.html.push_str(&figure_id(name));
+            self.html.push_str("\">");
+            return;
+        }
+        if let Some(name) = dest_url.strip_prefix("listing:") {
+            self.html
+                .push_str(r##"<a class="listingref" href="#listing-"##);
+            self.html.push_str(name);
+            self.html.push_str("\">");
+            return;
+        }
+        self.html.push_str("<a href=\"");
         self.html.push_str(&dest_url);
         self.html.push('"');
         if !id.is_empty() {
-            self.html.push_str(&" id=\"");
+            self.html.push_str(" id=\"");
             self.html.push_str(&id);
             self.html.push('"');
         }
         if !title.is_empty() {
-            self.html.push_str(&" title=\"");
+            self.html.push_str(" title=\"");
             self.html.push_str(&title);
             self.html.push('"');
         }
         self.html.push('>');
-        if dest_url.starts_with(&"https://")
-            || dest_url.starts_with(&"http://")
-            || dest_url.starts_with(&"#")
+        if dest_url.starts_with("https://")
+            || dest_url.starts_with("http://")
+            || dest_url.starts_with("#")
         {
         } else {
             self.links.push(dest_url.to_string());
         }
     }
 
-    fn gen_codeblock(&mut self, lang: CowStr<'_>, parser: &mut Parser<'_>, metas: &Vec<DocMeta>) {
-        if lang == CowStr::from("docdustry-docmeta") {
-            self.gen_codeblock_metainfo(parser);
-        } else if lang == CowStr::from("docdustry-doclist") {
-            self.gen_codeblock_doclist(parser, metas);
-        } else {
-            self.gen_codeblock_normal(lang, parser);
+    fn gen_codeblock(
+        &mut self,
+        info: CowStr<'_>,
+        parser: &mut Parser<'_>,
+        include_map: &Option<HashMap<String, String>>,
+        metas: &[DocMeta],
+    ) {
+        let info = CodeInfo::parse(&info);
+        match info.lang.as_str() {
+            "docdustry-docmeta" => self.gen_codeblock_metainfo(parser),
+            "docdustry-doclist" => self.gen_codeblock_doclist(parser, include_map, metas),
+            "docdustry-toc" => self.gen_codeblock_toc(parser),
+            "docdustry-glossary" => self.gen_codeblock_glossary(parser),
+            _ => self.gen_codeblock_normal(&info, parser),
+        }
+    }
+
+    /// Glossary entries like `term: definition`,
+    /// where indented lines continue the definition
+    fn gen_codeblock_glossary(&mut self, parser: &mut Parser<'_>) {
+        let mut text = String::new();
+        for event in parser.by_ref() {
+            match event {
+                Event::End(TagEnd::CodeBlock) => break,
+                Event::Text(t) => text.push_str(&t),
+                e => error!(
+                    "Unexpected in glossary block of {}: {:?}",
+                    self.src_path_rel.display(),
+                    e
+                ),
+            }
+        }
+        let start = self.glossary.len();
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            if line.starts_with(char::is_whitespace) {
+                match self.glossary[start..].last_mut() {
+                    Some((_, def)) => {
+                        def.push(' ');
+                        def.push_str(line.trim());
+                    }
+                    None => error!(
+                        "Glossary continuation without a term in {}: {}",
+                        self.src_path_rel.display(),
+                        line.trim()
+                    ),
+                }
+            } else if let Some((term, def)) = line.split_once(':') {
+                self.glossary
+                    .push((term.trim().to_string(), def.trim().to_string()));
+            } else {
+                error!(
+                    "Not a glossary entry like `term: definition` in {}: {}",
+                    self.src_path_rel.display(),
+                    line
+                );
+            }
+        }
+        self.html.push_str(r#"<dl class="glossary">"#);
+        for i in start..self.glossary.len() {
+            let (term, def) = &self.glossary[i];
+            // the anchors are on the glossary page
+            let mut html = String::from("<dt>");
+            escape_html(&mut html, term).unwrap();
+            html.push_str("</dt><dd>");
+            escape_html(&mut html, def).unwrap();
+            html.push_str("</dd>");
+            self.html.push_str(&html);
+        }
+        self.html.push_str("</dl>");
+    }
+
+    /// A placeholder for the table of contents, filled by finish_html
+    fn gen_codeblock_toc(&mut self, parser: &mut Parser<'_>) {
+        let mut depth = 6;
+        for event in parser.by_ref() {
+            match event {
+                Event::End(TagEnd::CodeBlock) => break,
+                Event::Text(t) => {
+                    for line in t.lines().filter(|l| !l.trim().is_empty()) {
+                        match line.split_once(':').map(|(k, v)| (k.trim(), v.trim())) {
+                            Some(("depth", v)) => match v.parse() {
+                                Ok(d) => depth = d,
+                                Err(_) => error!(
+                                    "Invalid toc depth in {}: {}",
+                                    self.src_path_rel.display(),
+                                    v
+                                ),
+                            },
+                            _ => error!(
+                                "Unknown toc option in {}: {}",
+                                self.src_path_rel.display(),
+                                line
+                            ),
+                        }
+                    }
+                }
+                e => error!(
+                    "Unexpected in toc block of {}: {:?}",
+                    self.src_path_rel.display(),
+                    e
+                ),
+            }
         }
+        self.html.push_str(&format!(
+            r#"<nav class="docdustry-toc" data-depth="{}"></nav>"#,
+            depth
+        ));
     }
 
     fn gen_codeblock_metainfo(&mut self, parser: &mut Parser<'_>) {
-        self.html.push_str(&r#"<details class=\"metainfo">"#);
-        self.html.push_str(&"<summary>doc meta info</summary>");
+        self.html.push_str(r#"<details class=\"metainfo">"#);
+        self.html.push_str("<summary>doc meta info</summary>");
         self.html
-            .push_str(&"<pre class=\"docdustry-docmeta\"><code>");
-        while let Some(event) = parser.next() {
+            .push_str("<pre class=\"docdustry-docmeta\"><code>");
+        for event in parser.by_ref() {
             match event {
                 Event::End(TagEnd::CodeBlock) => {
-                    self.html.push_str(&"</code></pre>");
+                    self.html.push_str("</code></pre>");
                     break;
                 }
                 Event::Text(t) => {
@@ -308,91 +785,206 @@ impl Doc {
                 _ => todo!(),
             }
         }
-        self.html.push_str(&"</details>");
+        self.html.push_str("</details>");
     }
 
-    fn gen_codeblock_doclist(&mut self, parser: &mut Parser<'_>, metas: &Vec<DocMeta>) {
-        let mut this_list: Vec<DocMeta> = metas.to_vec();
-        self.html.push_str(&r#"<ul class="doclist">"#);
-        while let Some(event) = parser.next() {
+    fn gen_codeblock_doclist(
+        &mut self,
+        parser: &mut Parser<'_>,
+        include_map: &Option<HashMap<String, String>>,
+        metas: &[DocMeta],
+    ) {
+        let mut text = String::new();
+        for event in parser.by_ref() {
             match event {
-                Event::End(TagEnd::CodeBlock) => {
-                    break;
+                Event::End(TagEnd::CodeBlock) => break,
+                Event::Text(t) => text.push_str(&t),
+                _ => todo!(),
+            }
+        }
+        let list = DocList::parse(&text, &self.did);
+        let map = match include_map {
+            Some(m) => m,
+            None => {
+                // needs the metadata of all documents
+                self.doclists.push(list);
+                self.redo = true;
+                return;
+            }
+        };
+        let docs = list.selection.select(metas);
+        match list.style {
+            ListStyle::List => {
+                self.html.push_str(r#"<ul class="doclist">"#);
+                for dm in docs {
+                    self.html.push_str("<li><a href=\"did:");
+                    self.html.push_str(&dm.did);
+                    self.html.push_str("\">");
+                    escape_html(&mut self.html, &dm.title).unwrap();
+                    self.html.push_str("</a>");
+                    if !dm.status.is_empty() {
+                        self.html.push(' ');
+                        self.html.push_str(&status_badge(&dm.status));
+                    }
+                    self.html.push_str("</li>");
+                    self.listed.push(dm.did.clone());
                 }
-                Event::Text(t) => {
-                    for line in t.lines() {
-                        if let Some((k, v)) = line.split_once(":") {
-                            let key = k.trim();
-                            let value: String = v.trim().to_string();
-                            if key == "only-if-tagged" {
-                                this_list = this_list
-                                    .into_iter()
-                                    .filter(|dm| dm.tags.contains(&value))
-                                    .collect::<Vec<DocMeta>>();
-                            } else if key == "skip-if-tagged" {
-                                this_list = this_list
-                                    .into_iter()
-                                    .filter(|dm| !dm.tags.contains(&value))
-                                    .collect::<Vec<DocMeta>>();
+                self.html.push_str("</ul>");
+            }
+            ListStyle::Table => {
+                self.html.push_str(r#"<table class="doclist"><thead><tr>"#);
+                for col in &list.columns {
+                    self.html.push_str("<th>");
+                    escape_html(&mut self.html, col).unwrap();
+                    self.html.push_str("</th>");
+                }
+                self.html.push_str("</tr></thead><tbody>");
+                for dm in docs {
+                    self.listed.push(dm.did.clone());
+                    self.html.push_str("<tr>");
+                    for col in &list.columns {
+                        self.html.push_str("<td>");
+                        if col == "title" || col == "did" {
+                            self.html.push_str("<a href=\"did:");
+                            self.html.push_str(&dm.did);
+                            self.html.push_str("\">");
+                            escape_html(&mut self.html, &dm.field_values(col).join(", ")).unwrap();
+                            self.html.push_str("</a>");
+                        } else if col == "status" {
+                            if !dm.status.is_empty() {
+                                self.html.push_str(&status_badge(&dm.status));
+                            }
+                        } else {
+                            for (i, v) in dm.field_values(col).iter().enumerate() {
+                                if i > 0 {
+                                    self.html.push_str(", ");
+                                }
+                                // relations to other documents
+                                match v.strip_prefix("did:") {
+                                    Some(target) => {
+                                        self.html.push_str("<a href=\"");
+                                        escape_html(&mut self.html, v).unwrap();
+                                        self.html.push_str("\">");
+                                        escape_html(&mut self.html, target).unwrap();
+                                        self.html.push_str("</a>");
+                                    }
+                                    None => escape_html(&mut self.html, v).unwrap(),
+                                }
                             }
                         }
+                        self.html.push_str("</td>");
                     }
+                    self.html.push_str("</tr>");
+                }
+                self.html.push_str("</tbody></table>");
+            }
+            ListStyle::Include => {
+                for dm in docs {
+                    if dm.did == self.did {
+                        continue;
+                    }
+                    let inc = Inclusion {
+                        did: dm.did.clone(),
+                        section: None,
+                        notitle: false,
+                    };
+                    self.gen_inclusion(&inc, map);
                 }
-                _ => todo!(),
             }
         }
-        for dm in this_list {
-            self.html.push_str(&"<li><a href=\"did:");
-            self.html.push_str(&dm.did);
-            self.html.push_str(&"\">");
-            self.html.push_str(&dm.title);
-            self.html.push_str(&"</a></li>");
-            self.includes.push(dm.did);
-        }
-        self.html.push_str(&"</ul>");
     }
 
-    fn gen_codeblock_normal(&mut self, lang: CowStr<'_>, parser: &mut Parser<'_>) {
-        self.html.push_str(&"<pre class=\"language-");
-        if lang.is_empty() {
-            self.html.push_str(&"unknown");
-        } else {
-            self.html.push_str(&lang);
-        }
-        self.html.push_str(&"\"><code>");
-        while let Some(event) = parser.next() {
+    /// A code listing, optionally as a figure with title and caption
+    fn gen_codeblock_normal(&mut self, info: &CodeInfo, parser: &mut Parser<'_>) {
+        let mut code = String::new();
+        for event in parser.by_ref() {
             match event {
-                Event::End(TagEnd::CodeBlock) => {
-                    self.html.push_str(&"</code></pre>");
-                    break;
-                }
-                Event::Text(t) => {
-                    escape_html(&mut self.html, &t).unwrap();
-                }
+                Event::End(TagEnd::CodeBlock) => break,
+                Event::Text(t) => code.push_str(&t),
                 _ => todo!(),
             }
         }
+        let is_figure = info.title.is_some() || info.caption.is_some() || info.id.is_some();
+        if is_figure {
+            self.html.push_str(r#"<figure class="listing""#);
+            if let Some(id) = &info.id {
+                self.html.push_str(" id=\"listing-");
+                escape_html(&mut self.html, id).unwrap();
+                self.html.push('"');
+            }
+            self.html.push('>');
+        }
+        if let Some(title) = &info.title {
+            self.html.push_str(r#"<div class="listing-title">"#);
+            escape_html(&mut self.html, title).unwrap();
+            self.html.push_str("</div>");
+        }
+        self.html.push_str("<pre class=\"language-");
+        if info.lang.is_empty() {
+            self.html.push_str("unknown");
+        } else {
+            escape_html(&mut self.html, &info.lang).unwrap();
+        }
+        self.html.push_str("\"><code>");
+        self.html.push_str(&highlight::render(
+            &info.lang,
+            &code,
+            info.linenos,
+            &info.marked,
+        ));
+        self.html.push_str("</code></pre>");
+        if let Some(caption) = &info.caption {
+            self.html
+                .push_str(r#"<figcaption><span class="listingnum" data-ref="listing-"#);
+            if let Some(id) = &info.id {
+                escape_html(&mut self.html, id).unwrap();
+            }
+            self.html.push_str("\"></span> ");
+            escape_html(&mut self.html, caption).unwrap();
+            self.html.push_str("</figcaption>");
+        }
+        if is_figure {
+            self.html.push_str("</figure>\n");
+        }
     }
 
     fn parse_meta(&mut self, meta: String) {
-        for line in meta.split("\n") {
-            if let Some((k, v)) = line.split_once(":") {
-                match k {
-                    "status" => {
-                        self.status = v.trim().to_string();
-                    }
-                    "id" => {
-                        self.did = v.trim().to_string();
-                    }
-                    "tag" => {
-                        self.tags.push(v.trim().to_string());
-                    }
-                    _ => (),
+        for (k, v) in meta_fields(&meta) {
+            match k {
+                "status" => {
+                    self.status = v.to_string();
+                }
+                "id" => {
+                    self.did = v.to_string();
+                }
+                "type" => {
+                    self.doc_type = v.to_string();
+                }
+                "tag" => {
+                    self.tags.push(v.to_string());
+                }
+                "toc" => {
+                    self.toc_enabled = v != "false";
+                }
+                "toc-depth" => match v.parse() {
+                    Ok(depth) => self.toc_depth = depth,
+                    Err(_) => warn!("Invalid toc-depth in {}: {}", self.did, v),
+                },
+                _ => {
+                    self.meta
+                        .entry(k.to_string())
+                        .or_default()
+                        .push(v.to_string());
                 }
             }
         }
     }
 
+    /// The path of the Markdown file
+    pub fn src_path(&self) -> PathBuf {
+        self.src_path_base.join(&self.src_path_rel)
+    }
+
     pub fn shorthash(&self) -> String {
         let dir = self.src_path_rel.parent().unwrap();
         let hash = md5::compute(dir.as_os_str().as_encoded_bytes());
@@ -423,82 +1015,415 @@ impl Doc {
     }
 }
 
-fn skip_img_rest(parser: &mut Parser<'_>) {
-    let n = parser.next();
-    match n {
-        Some(Event::Text(_)) => {
-            let nn = parser.next();
-            match nn {
-                Some(Event::End(TagEnd::Image)) => (),
+/// An inclusion like `did:foo?notitle#section`
+struct Inclusion {
+    did: String,
+    /// id of the heading whose section is included
+    section: Option<String>,
+    /// skip the H1 title of the included document
+    notitle: bool,
+}
+
+impl Inclusion {
+    fn parse(target: &str) -> Inclusion {
+        let did_end = target.find(['?', '#']).unwrap_or(target.len());
+        let mut section = None;
+        let mut notitle = false;
+        if let Some(i) = target.find('#') {
+            let s = &target[i + 1..];
+            section = Some(s[..s.find('?').unwrap_or(s.len())].to_string());
+        }
+        if let Some(i) = target.find('?') {
+            let q = &target[i + 1..];
+            for opt in q[..q.find('#').unwrap_or(q.len())].split('&') {
+                match opt {
+                    "notitle" => notitle = true,
+                    _ => warn!("Unknown inclusion option: {}", opt),
+                }
+            }
+        }
+        Inclusion {
+            did: target[..did_end].to_string(),
+            section,
+            notitle,
+        }
+    }
+}
+
+#[derive(PartialEq)]
+enum ListStyle {
+    List,
+    Table,
+    Include,
+}
+
+/// A docdustry-doclist block
+struct DocList {
+    selection: Selection,
+    style: ListStyle,
+    /// fields shown in table style
+    columns: Vec<String>,
+}
+
+impl DocList {
+    fn parse(text: &str, did: &str) -> DocList {
+        let mut list = DocList {
+            selection: Selection::new(),
+            style: ListStyle::List,
+            columns: vec!["did".to_string(), "title".to_string(), "status".to_string()],
+        };
+        for line in text.lines() {
+            let (key, value) = match line.split_once(':') {
+                Some((k, v)) => (k.trim(), v.trim()),
+                None => continue,
+            };
+            let filter = match key {
+                "only-if-tagged" => Expr::parse(&format!("tag = \"{}\"", value)),
+                "skip-if-tagged" => Expr::parse(&format!("tag != \"{}\"", value)),
+                "status" => Expr::parse(
+                    &value
+                        .split(',')
+                        .map(|s| format!("status = \"{}\"", s.trim()))
+                        .collect::<Vec<_>>()
+                        .join(" or "),
+                ),
+                "path" => Expr::parse(&format!("path ~ \"{}\"", value)),
+                "links-to" => Expr::parse(&format!("links-to = \"{}\"", value)),
+                "where" => Expr::parse(value),
+                "sort-by" => {
+                    list.selection.push_sort_by(value);
+                    continue;
+                }
+                "limit" => {
+                    match value.parse() {
+                        Ok(n) => list.selection.limit = Some(n),
+                        Err(_) => warn!("Invalid doclist limit in {}: {}", did, value),
+                    }
+                    continue;
+                }
+                "style" => {
+                    list.style = match value {
+                        "list" => ListStyle::List,
+                        "table" => ListStyle::Table,
+                        "include" => ListStyle::Include,
+                        _ => {
+                            warn!("Unknown doclist style in {}: {}", did, value);
+                            ListStyle::List
+                        }
+                    };
+                    continue;
+                }
+                "columns" => {
+                    list.columns = value.split(',').map(|c| c.trim().to_string()).collect();
+                    continue;
+                }
                 _ => {
-                    todo!("unforeseen: {:?}", n);
+                    warn!("Unknown doclist key in {}: {}", did, key);
+                    continue;
                 }
+            };
+            match filter {
+                Ok(e) => list.selection.filters.push(e),
+                Err(e) => warn!("Invalid doclist {} in {}: {}", key, did, e),
             }
         }
-        Some(Event::End(TagEnd::Image)) => (),
-        _ => {
-            todo!("unforeseen: {:?}", n);
+        list
+    }
+}
+
+/// The info string of a fenced code block like
+/// `rust title="main.rs" caption="Entry point" id=main linenos {2-4}`
+pub struct CodeInfo {
+    pub lang: String,
+    /// shown above the code, like a file name
+    title: Option<String>,
+    /// numbered caption below the code
+    caption: Option<String>,
+    /// for references like listing:main
+    id: Option<String>,
+    linenos: bool,
+    /// line ranges to highlight
+    marked: Vec<(usize, usize)>,
+    /// substitute variables in the code
+    pub vars: bool,
+}
+
+impl CodeInfo {
+    pub fn parse(info: &str) -> CodeInfo {
+        let mut ret = CodeInfo {
+            lang: String::new(),
+            title: None,
+            caption: None,
+            id: None,
+            linenos: false,
+            marked: vec![],
+            vars: false,
+        };
+        let mut rest = info.trim();
+        let lang_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let lang_end = rest[..lang_end].find('{').unwrap_or(lang_end);
+        ret.lang = rest[..lang_end].to_string();
+        rest = rest[lang_end..].trim_start();
+        while !rest.is_empty() {
+            if let Some(braced) = rest.strip_prefix('{') {
+                // line ranges and flags like {2-4,7 linenos}
+                let end = braced.find('}').unwrap_or(braced.len());
+                for attr in braced[..end].split_whitespace() {
+                    ret.set("", attr);
+                }
+                rest = braced[(end + 1).min(braced.len())..].trim_start();
+                continue;
+            }
+            let key_end = rest
+                .find(|c: char| c == '=' || c.is_whitespace())
+                .unwrap_or(rest.len());
+            let key = &rest[..key_end];
+            rest = &rest[key_end..];
+            let value = if let Some(v) = rest.strip_prefix('=') {
+                if let Some(quoted) = v.strip_prefix('"') {
+                    let end = quoted.find('"').unwrap_or(quoted.len());
+                    rest = &quoted[(end + 1).min(quoted.len())..];
+                    &quoted[..end]
+                } else {
+                    let end = v.find(char::is_whitespace).unwrap_or(v.len());
+                    rest = &v[end..];
+                    &v[..end]
+                }
+            } else {
+                ""
+            };
+            ret.set(key, value);
+            rest = rest.trim_start();
+        }
+        ret
+    }
+
+    fn set(&mut self, key: &str, value: &str) {
+        match key {
+            "title" => self.title = Some(value.to_string()),
+            "caption" => self.caption = Some(value.to_string()),
+            "id" => self.id = Some(value.to_string()),
+            "linenos" => self.linenos = value != "false",
+            "hl" => self.marked.extend(highlight::parse_line_ranges(value)),
+            "vars" => self.vars = value != "false",
+            // inside braces
+            "" if value == "linenos" => self.linenos = true,
+            "" if value == "vars" => self.vars = true,
+            "" => self.marked.extend(highlight::parse_line_ranges(value)),
+            _ => warn!("Unknown code block attribute: {}", key),
         }
     }
 }
 
-struct HtmlConverter {
+/// Consume the rest of an image and return its alt text
+fn img_alt_text(parser: &mut Parser<'_>) -> String {
+    let mut alt = String::new();
+    for event in parser.by_ref() {
+        match event {
+            Event::End(TagEnd::Image) => break,
+            Event::Text(t) | Event::Code(t) => alt.push_str(&t),
+            _ => (),
+        }
+    }
+    alt
+}
+
+/// HTML id of a figure, derived from the image file name
+fn figure_id(name: &str) -> String {
+    let stem = Path::new(name)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    format!("fig-{}", stem)
+}
+
+struct HtmlConverter<'a> {
+    cfg: &'a Config,
     docs: Vec<Doc>,
     id2index: HashMap<String, usize>,
     includes_docs: VecDeque<usize>,
+    /// DIDs of documents not in the variant
+    dropped: HashSet<String>,
+    /// keep the documents not in the variant
+    all_variants: bool,
 }
 
-#[derive(Clone)]
-struct DocMeta {
+/// What a doclist or query needs to know about every document
+pub struct DocMeta {
     did: String,
     title: String,
+    status: String,
+    doc_type: String,
     tags: Vec<String>,
+    path: String,
+    meta: BTreeMap<String, Vec<String>>,
+    links: Vec<String>,
+    /// documents linking to this one as link type and DID
+    backlinks: Vec<(String, String)>,
+}
+
+impl DocMeta {
+    /// Links to documents as link type and DID,
+    /// where the type is `link` in the text or the key of a doc meta relation
+    fn typed_links(&self) -> Vec<(String, String)> {
+        let text = self.links.iter().map(|l| ("link", l));
+        let relations = self
+            .meta
+            .iter()
+            .flat_map(|(k, vs)| vs.iter().map(move |v| (k.as_str(), v)));
+        let mut ret: Vec<(String, String)> = text
+            .chain(relations)
+            .filter_map(|(t, l)| {
+                let did = l.strip_prefix("did:")?.split('#').next().unwrap();
+                Some((t.to_string(), did.to_string()))
+            })
+            .collect();
+        ret.sort();
+        ret.dedup();
+        ret
+    }
+
+    /// DIDs of documents linked in the text or related in the doc meta info
+    fn link_targets(&self) -> Vec<String> {
+        let mut ret: Vec<String> = self.typed_links().into_iter().map(|(_, did)| did).collect();
+        ret.sort();
+        ret.dedup();
+        ret
+    }
+
+    fn backlink_sources(&self, link_type: Option<&str>) -> Vec<String> {
+        let mut ret: Vec<String> = self
+            .backlinks
+            .iter()
+            .filter(|(t, _)| link_type.is_none() || link_type == Some(t.as_str()))
+            .map(|(_, did)| did.clone())
+            .collect();
+        ret.sort();
+        ret.dedup();
+        ret
+    }
+}
+
+impl Fields for DocMeta {
+    fn field_values(&self, key: &str) -> Vec<String> {
+        match key {
+            "did" | "id" => vec![self.did.clone()],
+            "title" => vec![self.title.clone()],
+            "status" => vec![self.status.clone()],
+            "type" => vec![self.doc_type.clone()],
+            "tag" | "tags" => self.tags.clone(),
+            "path" => vec![self.path.clone()],
+            "links-to" => self.link_targets(),
+            "links" => vec![self.link_targets().len().to_string()],
+            "backlinks" => vec![self.backlink_sources(None).len().to_string()],
+            "backlink" => self.backlink_sources(None),
+            _ => match key.strip_prefix("backlink:") {
+                // like backlink:verifies for the documents verifying this one
+                Some(t) => self.backlink_sources(Some(t)),
+                None => self.meta.get(key).cloned().unwrap_or_default(),
+            },
+        }
+    }
 }
 
-impl HtmlConverter {
-    pub fn new() -> HtmlConverter {
+impl<'a> HtmlConverter<'a> {
+    pub fn new(cfg: &'a Config) -> HtmlConverter<'a> {
         HtmlConverter {
+            cfg,
             docs: vec![],
             includes_docs: VecDeque::new(),
             id2index: HashMap::new(),
+            dropped: HashSet::new(),
+            all_variants: false,
         }
     }
 
     fn read_md_files(&mut self, src_path_base: &Path) {
         self.collect_md_files(src_path_base);
         self.first_pass_across_all();
-        let metas: Vec<DocMeta> = doc2docmeta(&self.docs);
-        while !self.includes_docs.is_empty() {
-            let i = self.includes_docs.pop_front().unwrap();
-            let map = self.include_map_if_ready(&self.docs[i]);
+        let metas: Vec<DocMeta> = doc2docmeta(&self.docs, self.cfg);
+        for d in &mut self.docs {
+            // documents included by doclists
+            for list in std::mem::take(&mut d.doclists) {
+                if list.style == ListStyle::Include {
+                    for dm in list.selection.select(&metas) {
+                        if dm.did != d.did {
+                            d.includes.push(dm.did.clone());
+                        }
+                    }
+                }
+            }
+        }
+        let mut waiting = 0;
+        while let Some(i) = self.includes_docs.pop_front() {
+            let mut map = self.include_map_if_ready(&self.docs[i]);
             if map.is_none() {
-                continue;
+                if waiting <= self.includes_docs.len() {
+                    // try again after the included documents are done
+                    self.includes_docs.push_back(i);
+                    waiting += 1;
+                    continue;
+                }
+                warn!("Inclusion cycle: {}", self.docs[i].did);
+                map = Some(self.include_map_partial(&self.docs[i]));
             }
-            let ref mut d = self.docs[i];
+            waiting = 0;
+            let d = &mut self.docs[i];
             info!("Repeat HTML generation: {}", d.did);
-            d.parse_md(&d.raw.clone(), &map, &metas);
+            d.parse_md(&d.raw.clone(), &map, &metas, self.cfg);
+        }
+        self.flag_dropped_links();
+        for d in &mut self.docs {
+            d.finish_html();
         }
     }
 
     fn first_pass_across_all(&mut self) {
-        let mut i: usize = 0;
-        for d in &mut self.docs {
-            let result = d.gen_html();
-            if let Err(e) = result {
+        let cfg = self.cfg;
+        let all_variants = self.all_variants;
+        let dropped = &mut self.dropped;
+        self.docs.retain_mut(|d| {
+            if let Err(e) = d.gen_html(cfg) {
                 let path = d.src_path_base.join(&d.src_path_rel);
                 warn!("skip {}: {}", path.display(), e);
-                continue;
+                return false;
+            }
+            if !all_variants && !cfg.in_variant(&d.tags) {
+                info!("Not in variant: {}", d.did);
+                dropped.insert(d.did.clone());
+                return false;
             }
+            true
+        });
+        for (i, d) in self.docs.iter_mut().enumerate() {
             self.id2index.insert(d.did.clone(), i);
-            if !d.includes.is_empty() {
+            if d.redo {
                 self.includes_docs.push_back(i);
+                d.redo = false;
+            }
+        }
+    }
+
+    /// Warn about links to documents not in the variant and mark them
+    fn flag_dropped_links(&mut self) {
+        for d in &mut self.docs {
+            let mut targets: Vec<&str> = d
+                .links
+                .iter()
+                .filter_map(|l| l.strip_prefix("did:"))
+                .map(|l| l.split('#').next().unwrap())
+                .filter(|did| self.dropped.contains(*did))
+                .collect();
+            targets.dedup();
+            for did in targets {
+                warn!("{} links to {}, which is not in the variant", d.did, did);
+                d.html = mark_links_to(&d.html, did, "variant-excluded");
             }
-            i += 1;
         }
     }
 
     fn collect_md_files(&mut self, src_path_base: &Path) {
-        for result in Walk::new(&src_path_base) {
+        for result in Walk::new(src_path_base) {
             match result {
                 Ok(entry) => {
                     let t = entry.file_type().expect("file type");
@@ -516,7 +1441,7 @@ impl HtmlConverter {
                     };
                     let src_path_rel = entry
                         .path()
-                        .strip_prefix(&src_path_base)
+                        .strip_prefix(src_path_base)
                         .expect("is prefix")
                         .to_path_buf();
                     let doc = Doc::new(src_path_base.to_path_buf(), src_path_rel);
@@ -538,7 +1463,11 @@ impl HtmlConverter {
                     continue;
                 }
             };
-            let ref d2 = self.docs[j];
+            let d2 = &self.docs[j];
+            if self.cfg.is_excluded(&d2.status) {
+                // left out of the output, so not embedded either
+                continue;
+            }
             if d2.html.is_empty() {
                 // the included file is not finished yet (includes something itself?)
                 return None;
@@ -547,20 +1476,139 @@ impl HtmlConverter {
         }
         Some(map)
     }
+
+    /// hashmap of did->html for all finished documents included by d
+    fn include_map_partial(&self, d: &Doc) -> HashMap<String, String> {
+        let mut map: HashMap<String, String> = HashMap::new();
+        for did in &d.includes {
+            if let Some(j) = self.id2index.get(did) {
+                let d2 = &self.docs[*j];
+                if !d2.html.is_empty() && !self.cfg.is_excluded(&d2.status) {
+                    map.insert(did.clone(), d2.html.clone());
+                }
+            }
+        }
+        map
+    }
+}
+
+/// Links to a document missing from the output with a class
+pub fn mark_links_to(html: &str, did: &str, class: &str) -> String {
+    let mut ret = html.to_string();
+    for end in ['"', '#'] {
+        ret = ret.replace(
+            &format!("<a href=\"did:{}{}", did, end),
+            &format!("<a class=\"{}\" href=\"did:{}{}", class, did, end),
+        );
+    }
+    ret
+}
+
+/// A label showing the status of a document
+pub fn status_badge(status: &str) -> String {
+    let mut ret = String::from(r#"<span class="status-badge status-"#);
+    escape_html(&mut ret, status).unwrap();
+    ret.push_str(r#"">"#);
+    escape_html(&mut ret, status).unwrap();
+    ret.push_str("</span>");
+    ret
 }
 
-fn doc2docmeta(docs: &Vec<Doc>) -> Vec<DocMeta> {
-    docs.iter()
+/// Metadata of the documents which are part of the output
+pub fn doc2docmeta(docs: &[Doc], cfg: &Config) -> Vec<DocMeta> {
+    let mut metas: Vec<DocMeta> = docs
+        .iter()
+        .filter(|d| !cfg.is_excluded(&d.status))
         .map(|d| DocMeta {
             did: d.did.clone(),
             title: d.title.clone(),
+            status: d.status.clone(),
+            doc_type: d.doc_type.clone(),
             tags: d.tags.clone(),
+            path: d.src_path_rel.to_string_lossy().to_string(),
+            meta: d.meta.clone(),
+            links: d.links.clone(),
+            backlinks: vec![],
         })
+        .collect();
+    let mut backlinks: HashMap<String, Vec<(String, String)>> = HashMap::new();
+    for dm in &metas {
+        for (t, target) in dm.typed_links() {
+            backlinks
+                .entry(target)
+                .or_default()
+                .push((t, dm.did.clone()));
+        }
+    }
+    for dm in &mut metas {
+        dm.backlinks = backlinks.remove(&dm.did).unwrap_or_default();
+    }
+    metas
+}
+
+/// The trimmed `key: value` lines of doc meta info, other lines are ignored
+pub fn meta_fields(meta: &str) -> Vec<(&str, &str)> {
+    meta.split('\n')
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim(), v.trim()))
         .collect()
 }
 
-pub fn read_md_files(docs: &mut Vec<Doc>, src_path_base: &Path) {
-    let mut conv = HtmlConverter::new();
+pub fn read_md_files(docs: &mut Vec<Doc>, src_path_base: &Path, cfg: &Config) {
+    let mut conv = HtmlConverter::new(cfg);
     conv.read_md_files(src_path_base);
     docs.append(&mut conv.docs);
 }
+
+/// Read the documents of all variants, for commands changing the sources
+pub fn read_all_md_files(docs: &mut Vec<Doc>, src_path_base: &Path, cfg: &Config) {
+    let mut conv = HtmlConverter::new(cfg);
+    conv.all_variants = true;
+    conv.read_md_files(src_path_base);
+    docs.append(&mut conv.docs);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc_with_html(html: &str) -> Doc {
+        let mut d = Doc::new(PathBuf::new(), PathBuf::from("test.md"));
+        d.html = html.to_string();
+        d
+    }
+
+    #[test]
+    fn repeated_figures() {
+        let fig = r#"<figure id="fig-a"><figcaption><span class="fignum" data-ref="fig-a"></span> A</figcaption></figure>"#;
+        let mut d = doc_with_html(&format!(
+            r##"{}{}<a class="figref" href="#fig-a"></a>"##,
+            fig, fig
+        ));
+        d.number_captions("fig", "Figure");
+        assert_eq!(d.html.matches(r#"<figure id="fig-a">"#).count(), 1);
+        assert!(d.html.contains(r#"<figure id="fig-a-1">"#));
+        assert!(d.html.contains(r#"data-ref="fig-a-1">Figure 2:"#));
+        assert!(d.html.contains(r##"href="#fig-a">Figure 1</a>"##));
+    }
+
+    #[test]
+    fn caption_markers_in_raw_html() {
+        let html = r##"<span class="fignum" data-ref="x <a class="figref" href="#y"##;
+        let mut d = doc_with_html(html);
+        d.number_captions("fig", "Figure");
+        assert_eq!(d.html, html);
+    }
+
+    #[test]
+    fn toc_markers_in_raw_html() {
+        let html = r#"<h2 id="a">A</h2><nav class="docdustry-toc" data-depth="2"></nav><nav class="docdustry-toc" data-depth="x"#;
+        let mut d = doc_with_html(html);
+        d.finish_html();
+        assert!(d.html.contains(r#"<nav class="docdustry-toc-block"><ul>"#));
+        assert!(d.html.contains(r##"<a href="#a">A</a>"##));
+        assert!(d
+            .html
+            .ends_with(r#"<nav class="docdustry-toc" data-depth="x"#));
+    }
+}
@@ -0,0 +1,255 @@
+//! The glossary page and links from terms in the text to it
+
+use log::warn;
+use pulldown_cmark_escape::escape_html;
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use crate::gen_html::Doc;
+use crate::headings;
+
+/// DID of the generated glossary page
+pub const GLOSSARY_DID: &str = "glossary";
+
+pub struct Term {
+    pub term: String,
+    pub definition: String,
+    /// the document defining the term
+    pub did: String,
+    /// anchor on the glossary page, unique among the terms
+    id: String,
+}
+
+/// The terms of all glossary blocks, sorted
+pub fn collect(docs: &[Doc]) -> Vec<Term> {
+    let mut terms: Vec<Term> = vec![];
+    for d in docs {
+        for (term, definition) in &d.glossary {
+            if let Some(t) = terms.iter().find(|t| t.term == *term) {
+                warn!("Term {} defined in {} and {}", term, t.did, d.did);
+                continue;
+            }
+            terms.push(Term {
+                term: term.clone(),
+                definition: definition.clone(),
+                did: d.did.clone(),
+                id: String::new(),
+            });
+        }
+    }
+    terms.sort_by_key(|t| t.term.to_lowercase());
+    // terms like API and api have the same slug
+    let mut ids: HashSet<String> = HashSet::new();
+    for t in &mut terms {
+        let slug = format!("term-{}", headings::slugify(&t.term));
+        let mut id = slug.clone();
+        let mut n = 0;
+        while !ids.insert(id.clone()) {
+            n += 1;
+            id = format!("{}-{}", slug, n);
+        }
+        t.id = id;
+    }
+    terms
+}
+
+/// A page listing all terms
+pub fn glossary_doc(terms: &[Term]) -> Doc {
+    let mut d = Doc::new(PathBuf::new(), PathBuf::from("docdustry_glossary.md"));
+    d.did = GLOSSARY_DID.to_string();
+    d.title = "Glossary".to_string();
+    d.url = d.rel_url();
+    d.html
+        .push_str(r#"<h1 id="glossary">Glossary</h1><dl class="glossary">"#);
+    for t in terms {
+        d.html.push_str(&format!(r#"<dt id="{}">"#, t.id));
+        escape_html(&mut d.html, &t.term).unwrap();
+        d.html.push_str("</dt><dd>");
+        escape_html(&mut d.html, &t.definition).unwrap();
+        d.html.push_str(&format!(
+            r#" <a class="term-source" href="did:{}"></a>"#,
+            t.did
+        ));
+        d.html.push_str("</dd>");
+    }
+    d.html.push_str("</dl>");
+    d
+}
+
+/// Elements whose text is not searched for terms
+const SKIP_TAGS: &[&str] = &[
+    "a", "code", "pre", "h1", "h2", "h3", "h4", "h5", "h6", "dt", "details", "script", "style",
+    "svg",
+];
+
+/// The tag name of an opening or closing tag starting at `<`, and whether it closes
+fn tag_name(tag: &str) -> (&str, bool) {
+    let (inner, closing) = match tag[1..].strip_prefix('/') {
+        Some(rest) => (rest, true),
+        None => (&tag[1..], false),
+    };
+    let end = inner
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(inner.len());
+    (&inner[..end], closing)
+}
+
+/// Link the first occurrence of each term in each document to the glossary, matching case
+pub fn link_terms(docs: &mut [Doc], terms: &[Term]) {
+    // terms as they appear in HTML text
+    let escaped: Vec<String> = terms
+        .iter()
+        .map(|t| {
+            let mut s = String::new();
+            escape_html(&mut s, &t.term).unwrap();
+            s
+        })
+        .collect();
+    for d in docs.iter_mut() {
+        let mut linked = vec![false; terms.len()];
+        let html = std::mem::take(&mut d.html);
+        let mut ret = String::with_capacity(html.len());
+        let mut skip_depth = 0;
+        let mut pos = 0;
+        while pos < html.len() {
+            if html[pos..].starts_with('<') {
+                let end = html[pos..].find('>').map_or(html.len(), |i| pos + i + 1);
+                let tag = &html[pos..end];
+                let (name, closing) = tag_name(tag);
+                if SKIP_TAGS.contains(&name) && !tag.ends_with("/>") {
+                    if closing {
+                        skip_depth -= 1;
+                    } else {
+                        skip_depth += 1;
+                    }
+                }
+                ret.push_str(tag);
+                pos = end;
+                continue;
+            }
+            let end = html[pos..].find('<').map_or(html.len(), |i| pos + i);
+            let text = &html[pos..end];
+            if skip_depth > 0 {
+                ret.push_str(text);
+            } else {
+                link_text(text, terms, &escaped, &mut linked, &mut ret);
+            }
+            pos = end;
+        }
+        d.html = ret;
+    }
+}
+
+/// Whether the text at `start..end` is a whole word
+fn is_word(text: &str, start: usize, end: usize) -> bool {
+    let before = text[..start].chars().next_back();
+    let after = text[end..].chars().next();
+    !before.is_some_and(|c| c.is_alphanumeric()) && !after.is_some_and(|c| c.is_alphanumeric())
+}
+
+/// Link terms in a piece of HTML text, each only if not linked yet
+fn link_text(
+    text: &str,
+    terms: &[Term],
+    escaped: &[String],
+    linked: &mut [bool],
+    ret: &mut String,
+) {
+    let mut pos = 0;
+    loop {
+        // the earliest match, the longest term if several start there
+        let mut best: Option<(usize, usize)> = None;
+        for (i, term) in escaped.iter().enumerate() {
+            if linked[i] || term.is_empty() {
+                continue;
+            }
+            let mut from = pos;
+            while let Some(off) = text[from..].find(term.as_str()) {
+                let start = from + off;
+                let end = start + term.len();
+                if is_word(text, start, end) {
+                    let better = match best {
+                        None => true,
+                        Some((s, j)) => start < s || (start == s && term.len() > escaped[j].len()),
+                    };
+                    if better {
+                        best = Some((start, i));
+                    }
+                    break;
+                }
+                from = start + text[start..].chars().next().map_or(1, |c| c.len_utf8());
+            }
+        }
+        let (start, i) = match best {
+            Some(b) => b,
+            None => break,
+        };
+        let end = start + escaped[i].len();
+        ret.push_str(&text[pos..start]);
+        ret.push_str(&format!(
+            r#"<a class="term" href="did:{}#{}" title=""#,
+            GLOSSARY_DID, terms[i].id
+        ));
+        escape_html(&mut *ret, &terms[i].definition).unwrap();
+        ret.push_str(r#"">"#);
+        ret.push_str(&text[start..end]);
+        ret.push_str("</a>");
+        linked[i] = true;
+        pos = end;
+    }
+    ret.push_str(&text[pos..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc(did: &str, html: &str, glossary: &[(&str, &str)]) -> Doc {
+        let mut d = Doc::new(PathBuf::new(), PathBuf::from(format!("{}.md", did)));
+        d.did = did.to_string();
+        d.html = html.to_string();
+        d.glossary = glossary
+            .iter()
+            .map(|(t, def)| (t.to_string(), def.to_string()))
+            .collect();
+        d
+    }
+
+    #[test]
+    fn terms_differing_in_case() {
+        let docs = [
+            doc("a", "", &[("API", "interface"), ("DID", "id")]),
+            doc("b", "", &[("api", "apiary"), ("DID", "again")]),
+        ];
+        let terms = collect(&docs);
+        let found: Vec<(&str, &str, &str)> = terms
+            .iter()
+            .map(|t| (t.term.as_str(), t.did.as_str(), t.id.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("API", "a", "term-api"),
+                ("api", "b", "term-api-1"),
+                ("DID", "a", "term-did")
+            ]
+        );
+        let page = glossary_doc(&terms);
+        assert_eq!(page.html.matches(r#"id="term-api""#).count(), 1);
+    }
+
+    #[test]
+    fn linking() {
+        let terms = collect(&[doc("a", "", &[("API", "interface")])]);
+        let mut docs = [doc(
+            "b",
+            "<p>an api, <code>API</code>, <svg><text>API</text></svg> and API, API</p>",
+            &[],
+        )];
+        link_terms(&mut docs, &terms);
+        assert_eq!(
+            docs[0].html,
+            r##"<p>an api, <code>API</code>, <svg><text>API</text></svg> and <a class="term" href="did:glossary#term-api" title="interface">API</a>, API</p>"##
+        );
+    }
+}
@@ -0,0 +1,391 @@
+//! Helpers working on the headings of generated HTML
+
+use std::collections::HashSet;
+
+/// A tag relevant for the outline of generated HTML
+enum Mark {
+    Heading(HeadingTag),
+    /// byte offset of an `<article` opening tag
+    ArticleStart(usize),
+    /// byte offset of an `</article>` closing tag
+    ArticleEnd(usize),
+}
+
+/// A heading tag found in HTML
+struct HeadingTag {
+    /// byte offset of the `<hN` opening tag
+    start: usize,
+    /// byte offset right after the opening tag
+    content_start: usize,
+    level: usize,
+}
+
+/// Find the next heading or inclusion article tag at or after `from`
+fn next_mark(html: &str, from: usize) -> Option<Mark> {
+    let bytes = html.as_bytes();
+    let mut i = from;
+    while let Some(off) = html[i..].find('<') {
+        i += off;
+        let rest = &html[i..];
+        if rest.starts_with("<article") {
+            return Some(Mark::ArticleStart(i));
+        } else if rest.starts_with("</article") {
+            return Some(Mark::ArticleEnd(i));
+        } else if bytes.len() > i + 3
+            && bytes[i + 1] == b'h'
+            && (b'1'..=b'6').contains(&bytes[i + 2])
+            && (bytes[i + 3] == b'>' || bytes[i + 3] == b' ')
+        {
+            let end = rest.find('>')?;
+            return Some(Mark::Heading(HeadingTag {
+                start: i,
+                content_start: i + end + 1,
+                level: (bytes[i + 2] - b'0') as usize,
+            }));
+        }
+        i += 1;
+    }
+    None
+}
+
+/// The value of the id attribute in an opening tag like `<h2 id="foo">`
+fn tag_id(tag: &str) -> Option<&str> {
+    let start = tag.find(" id=\"")? + 5;
+    let len = tag[start..].find('"')?;
+    Some(&tag[start..start + len])
+}
+
+/// Extract the section starting with the heading with the given id
+/// until the next heading of the same or a higher level.
+pub fn extract_section<'a>(html: &'a str, id: &str) -> Option<&'a str> {
+    let mut pos = 0;
+    let head = loop {
+        match next_mark(html, pos)? {
+            Mark::Heading(h) => {
+                if tag_id(&html[h.start..h.content_start]) == Some(id) {
+                    break h;
+                }
+                pos = h.content_start;
+            }
+            Mark::ArticleStart(i) | Mark::ArticleEnd(i) => pos = i + 1,
+        }
+    };
+    let mut depth = 0;
+    let mut pos = head.content_start;
+    while let Some(mark) = next_mark(html, pos) {
+        match mark {
+            Mark::Heading(h) => {
+                if depth == 0 && h.level <= head.level {
+                    return Some(&html[head.start..h.start]);
+                }
+                pos = h.content_start;
+            }
+            Mark::ArticleStart(i) => {
+                depth += 1;
+                pos = i + 1;
+            }
+            Mark::ArticleEnd(i) => {
+                if depth == 0 {
+                    // the inclusion containing the section ends
+                    return Some(&html[head.start..i]);
+                }
+                depth -= 1;
+                pos = i + 1;
+            }
+        }
+    }
+    Some(&html[head.start..])
+}
+
+/// Remove the first H1 heading outside of inclusions, which is the document title
+pub fn strip_title(html: &str) -> String {
+    let mut depth: usize = 0;
+    let mut pos = 0;
+    while let Some(mark) = next_mark(html, pos) {
+        match mark {
+            Mark::Heading(h) => {
+                if h.level == 1 && depth == 0 {
+                    if let Some(len) = html[h.content_start..].find("</h1>") {
+                        let end = h.content_start + len + 5;
+                        let mut ret = String::with_capacity(html.len());
+                        ret.push_str(&html[..h.start]);
+                        ret.push_str(&html[end..]);
+                        return ret;
+                    }
+                }
+                pos = h.content_start;
+            }
+            Mark::ArticleStart(i) => {
+                depth += 1;
+                pos = i + 1;
+            }
+            Mark::ArticleEnd(i) => {
+                depth = depth.saturating_sub(1);
+                pos = i + 1;
+            }
+        }
+    }
+    html.to_string()
+}
+
+/// Change the level of all headings by `delta`, clamped to H1..H6
+pub fn shift_headings(html: &str, delta: i32) -> String {
+    if delta == 0 {
+        return html.to_string();
+    }
+    let bytes = html.as_bytes();
+    let mut ret = String::with_capacity(html.len());
+    let mut copied = 0;
+    let mut i = 0;
+    while let Some(off) = html[i..].find('<') {
+        i += off;
+        let digit = if bytes.get(i + 1) == Some(&b'/') {
+            i + 3
+        } else {
+            i + 2
+        };
+        if bytes.len() > digit + 1
+            && bytes[digit - 1] == b'h'
+            && (b'1'..=b'6').contains(&bytes[digit])
+            && (bytes[digit + 1] == b'>' || bytes[digit + 1] == b' ')
+        {
+            let level = (bytes[digit] - b'0') as i32 + delta;
+            ret.push_str(&html[copied..digit]);
+            ret.push_str(&level.clamp(1, 6).to_string());
+            copied = digit + 1;
+        }
+        i += 1;
+    }
+    ret.push_str(&html[copied..]);
+    ret
+}
+
+/// The level of the first heading, if there is one
+pub fn first_heading_level(html: &str) -> Option<usize> {
+    let mut pos = 0;
+    loop {
+        match next_mark(html, pos)? {
+            Mark::Heading(h) => return Some(h.level),
+            Mark::ArticleStart(i) | Mark::ArticleEnd(i) => pos = i + 1,
+        }
+    }
+}
+
+/// Derive a heading id from its text
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    while slug.ends_with('-') {
+        slug.pop();
+    }
+    if slug.is_empty() {
+        slug.push_str("section");
+    }
+    slug
+}
+
+/// Rename heading ids which occur more than once,
+/// which happens if included documents use the same headings.
+pub fn dedup_ids(html: &str) -> String {
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut ret = String::with_capacity(html.len());
+    let mut copied = 0;
+    let mut pos = 0;
+    while let Some(mark) = next_mark(html, pos) {
+        let h = match mark {
+            Mark::Heading(h) => h,
+            Mark::ArticleStart(i) | Mark::ArticleEnd(i) => {
+                pos = i + 1;
+                continue;
+            }
+        };
+        pos = h.content_start;
+        let id = match tag_id(&html[h.start..h.content_start]) {
+            Some(id) => id,
+            None => continue,
+        };
+        if seen.insert(id.to_string()) {
+            continue;
+        }
+        let mut n = 1;
+        let mut new_id = format!("{}-{}", id, n);
+        while seen.contains(&new_id) || html.contains(&format!(" id=\"{}\"", new_id)) {
+            n += 1;
+            new_id = format!("{}-{}", id, n);
+        }
+        seen.insert(new_id.clone());
+        let end = html[h.content_start..]
+            .find("</h")
+            .map_or(html.len(), |i| h.content_start + i);
+        ret.push_str(&html[copied..h.start]);
+        let heading = &html[h.start..end];
+        ret.push_str(
+            &heading
+                .replacen(
+                    &format!(" id=\"{}\"", id),
+                    &format!(" id=\"{}\"", new_id),
+                    1,
+                )
+                .replacen(
+                    &format!("href=\"#{}\"", id),
+                    &format!("href=\"#{}\"", new_id),
+                    1,
+                ),
+        );
+        copied = end;
+        pos = end;
+    }
+    ret.push_str(&html[copied..]);
+    ret
+}
+
+/// A heading of a page
+pub struct OutlineEntry {
+    pub level: usize,
+    pub id: String,
+    /// the heading content as HTML without tags
+    pub text: String,
+}
+
+/// All headings with id in order of appearance
+pub fn outline(html: &str) -> Vec<OutlineEntry> {
+    let mut ret = vec![];
+    let mut pos = 0;
+    while let Some(mark) = next_mark(html, pos) {
+        let h = match mark {
+            Mark::Heading(h) => h,
+            Mark::ArticleStart(i) | Mark::ArticleEnd(i) => {
+                pos = i + 1;
+                continue;
+            }
+        };
+        pos = h.content_start;
+        let id = match tag_id(&html[h.start..h.content_start]) {
+            Some(id) => id.to_string(),
+            None => continue,
+        };
+        let end = html[h.content_start..]
+            .find("</h")
+            .map_or(html.len(), |i| h.content_start + i);
+        ret.push(OutlineEntry {
+            level: h.level,
+            id,
+            text: strip_tags(&html[h.content_start..end]),
+        });
+    }
+    ret
+}
+
+fn strip_tags(html: &str) -> String {
+    let mut ret = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag => ret.push(c),
+            _ => (),
+        }
+    }
+    ret
+}
+
+/// Table of contents as nested lists in a nav element
+pub fn toc(outline: &[OutlineEntry], depth: usize) -> String {
+    if !outline.iter().any(|e| e.level <= depth) {
+        return String::new();
+    }
+    format!(
+        r#"<nav id="docdustry-toc">{}</nav>"#,
+        toc_list(outline, depth)
+    )
+}
+
+/// Table of contents as nested lists
+pub fn toc_list(outline: &[OutlineEntry], depth: usize) -> String {
+    let mut ret = String::new();
+    let mut level = 0;
+    for e in outline.iter().filter(|e| e.level <= depth) {
+        if e.level <= level {
+            ret.push_str("</li>");
+        }
+        while level < e.level {
+            ret.push_str("<ul>");
+            level += 1;
+            if level < e.level {
+                ret.push_str("<li>");
+            }
+        }
+        while level > e.level {
+            ret.push_str("</ul></li>");
+            level -= 1;
+        }
+        ret.push_str(&format!(r##"<li><a href="#{}">{}</a>"##, e.id, e.text));
+    }
+    while level > 0 {
+        ret.push_str("</li></ul>");
+        level -= 1;
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HTML: &str = r#"<h1 id="t">T</h1><p>a</p><h2 id="s">S</h2><p>b</p><article><h1 id="i">I</h1></article><h3 id="x">X</h3><h2 id="u">U</h2>"#;
+
+    #[test]
+    fn slugs() {
+        assert_eq!(slugify("Changes from Markdown"), "changes-from-markdown");
+        assert_eq!(slugify("  C++ & Rust!"), "c-rust");
+        assert_eq!(slugify("Über"), "über");
+        assert_eq!(slugify("!!"), "section");
+    }
+
+    #[test]
+    fn sections() {
+        assert_eq!(
+            extract_section(HTML, "s"),
+            Some(
+                r#"<h2 id="s">S</h2><p>b</p><article><h1 id="i">I</h1></article><h3 id="x">X</h3>"#
+            )
+        );
+        assert_eq!(extract_section(HTML, "i"), Some(r#"<h1 id="i">I</h1>"#));
+        assert_eq!(extract_section(HTML, "nope"), None);
+    }
+
+    #[test]
+    fn titles_and_levels() {
+        assert!(strip_title(HTML).starts_with("<p>a</p>"));
+        assert!(strip_title(HTML).contains(r#"<h1 id="i">"#));
+        let shifted = shift_headings(r#"<h1 id="a">A</h1><h6>B</h6><hr>"#, 1);
+        assert_eq!(shifted, r#"<h2 id="a">A</h2><h6>B</h6><hr>"#);
+        assert_eq!(first_heading_level("<p>x</p><h3>y</h3>"), Some(3));
+    }
+
+    #[test]
+    fn duplicate_ids() {
+        let html = r##"<h2 id="a"><a href="#a">A</a></h2><h2 id="a">A</h2><h2 id="a-1">B</h2>"##;
+        assert_eq!(
+            dedup_ids(html),
+            r##"<h2 id="a"><a href="#a">A</a></h2><h2 id="a-2">A</h2><h2 id="a-1">B</h2>"##
+        );
+    }
+
+    #[test]
+    fn tables_of_contents() {
+        let entries = outline(HTML);
+        let ids: Vec<&str> = entries.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, ["t", "s", "i", "x", "u"]);
+        let list = toc_list(&entries, 2);
+        assert!(list.contains(r##"<a href="#u">U</a>"##));
+        assert!(!list.contains(r##"href="#x""##));
+    }
+}
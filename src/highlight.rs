@@ -0,0 +1,626 @@
+//! Syntax highlighting of code blocks
+//!
+//! The tokens become spans with CSS classes like `hl-kw`,
+//! which the theme gives colors.
+
+use pulldown_cmark_escape::escape_html;
+
+/// How to tokenize a language
+struct Lang {
+    keywords: &'static [&'static str],
+    /// keywords like true or null
+    literals: &'static [&'static str],
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    /// string delimiters, longest first
+    strings: &'static [&'static str],
+    /// letters of string prefixes like `f` in `f"{x}"`
+    string_prefixes: &'static str,
+    /// identifiers starting upper case are types
+    upper_types: bool,
+    /// `foo!` is a macro and `#[..]` an attribute
+    rust_syntax: bool,
+    /// lines starting with `#` are preprocessor directives
+    preprocessor: bool,
+    /// `@foo` is a decorator
+    decorators: bool,
+    /// `$foo` is a variable
+    variables: bool,
+    /// words or strings followed by a colon are keys
+    keys: bool,
+}
+
+const RUST: Lang = Lang {
+    keywords: &[
+        "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+        "extern", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut",
+        "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait", "type",
+        "unsafe", "use", "where", "while",
+    ],
+    literals: &["true", "false"],
+    line_comments: &["//"],
+    block_comment: Some(("/*", "*/")),
+    strings: &["\""],
+    string_prefixes: "",
+    upper_types: true,
+    rust_syntax: true,
+    preprocessor: false,
+    decorators: false,
+    variables: false,
+    keys: false,
+};
+
+const C: Lang = Lang {
+    keywords: &[
+        "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else",
+        "enum", "extern", "float", "for", "goto", "if", "inline", "int", "long", "register",
+        "restrict", "return", "short", "signed", "sizeof", "static", "struct", "switch", "typedef",
+        "union", "unsigned", "void", "volatile", "while",
+    ],
+    literals: &["NULL", "true", "false"],
+    line_comments: &["//"],
+    block_comment: Some(("/*", "*/")),
+    strings: &["\"", "'"],
+    string_prefixes: "",
+    upper_types: false,
+    rust_syntax: false,
+    preprocessor: true,
+    decorators: false,
+    variables: false,
+    keys: false,
+};
+
+const CPP: Lang = Lang {
+    keywords: &[
+        "alignas",
+        "alignof",
+        "auto",
+        "bool",
+        "break",
+        "case",
+        "catch",
+        "char",
+        "class",
+        "const",
+        "constexpr",
+        "const_cast",
+        "continue",
+        "decltype",
+        "default",
+        "delete",
+        "do",
+        "double",
+        "dynamic_cast",
+        "else",
+        "enum",
+        "explicit",
+        "export",
+        "extern",
+        "float",
+        "for",
+        "friend",
+        "goto",
+        "if",
+        "inline",
+        "int",
+        "long",
+        "mutable",
+        "namespace",
+        "new",
+        "noexcept",
+        "operator",
+        "override",
+        "private",
+        "protected",
+        "public",
+        "register",
+        "reinterpret_cast",
+        "return",
+        "short",
+        "signed",
+        "sizeof",
+        "static",
+        "static_assert",
+        "static_cast",
+        "struct",
+        "switch",
+        "template",
+        "this",
+        "throw",
+        "try",
+        "typedef",
+        "typename",
+        "union",
+        "unsigned",
+        "using",
+        "virtual",
+        "void",
+        "volatile",
+        "while",
+    ],
+    literals: &["nullptr", "NULL", "true", "false"],
+    line_comments: &["//"],
+    block_comment: Some(("/*", "*/")),
+    strings: &["\"", "'"],
+    string_prefixes: "",
+    upper_types: false,
+    rust_syntax: false,
+    preprocessor: true,
+    decorators: false,
+    variables: false,
+    keys: false,
+};
+
+const PYTHON: Lang = Lang {
+    keywords: &[
+        "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+        "elif", "else", "except", "finally", "for", "from", "global", "if", "import", "in", "is",
+        "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while", "with",
+        "yield",
+    ],
+    literals: &["True", "False", "None"],
+    line_comments: &["#"],
+    block_comment: None,
+    strings: &["\"\"\"", "'''", "\"", "'"],
+    string_prefixes: "rbfu",
+    upper_types: true,
+    rust_syntax: false,
+    preprocessor: false,
+    decorators: true,
+    variables: false,
+    keys: false,
+};
+
+const JSON: Lang = Lang {
+    keywords: &[],
+    literals: &["true", "false", "null"],
+    line_comments: &[],
+    block_comment: None,
+    strings: &["\""],
+    string_prefixes: "",
+    upper_types: false,
+    rust_syntax: false,
+    preprocessor: false,
+    decorators: false,
+    variables: false,
+    keys: true,
+};
+
+const YAML: Lang = Lang {
+    keywords: &[],
+    literals: &["true", "false", "null", "yes", "no", "on", "off", "~"],
+    line_comments: &["#"],
+    block_comment: None,
+    strings: &["\"", "'"],
+    string_prefixes: "",
+    upper_types: false,
+    rust_syntax: false,
+    preprocessor: false,
+    decorators: false,
+    variables: false,
+    keys: true,
+};
+
+const SHELL: Lang = Lang {
+    keywords: &[
+        "case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if",
+        "in", "local", "return", "select", "then", "until", "while",
+    ],
+    literals: &["true", "false"],
+    line_comments: &["#"],
+    block_comment: None,
+    strings: &["\"", "'"],
+    string_prefixes: "",
+    upper_types: false,
+    rust_syntax: false,
+    preprocessor: false,
+    decorators: false,
+    variables: true,
+    keys: false,
+};
+
+fn lang_by_name(name: &str) -> Option<&'static Lang> {
+    match name {
+        "rust" | "rs" => Some(&RUST),
+        "c" | "h" => Some(&C),
+        "cpp" | "c++" | "cxx" | "cc" | "hpp" => Some(&CPP),
+        "python" | "py" => Some(&PYTHON),
+        "json" => Some(&JSON),
+        "yaml" | "yml" => Some(&YAML),
+        "shell" | "sh" | "bash" | "zsh" | "console" => Some(&SHELL),
+        _ => None,
+    }
+}
+
+/// A piece of code with an optional CSS class suffix
+struct Token<'a> {
+    class: Option<&'static str>,
+    text: &'a str,
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Byte length of the identifier at the start of s
+fn ident_len(s: &str) -> usize {
+    s.find(|c: char| !is_ident_char(c)).unwrap_or(s.len())
+}
+
+/// Byte length of a string starting with delimiter `delim` at the start of s
+fn string_len(s: &str, delim: &str) -> usize {
+    let mut i = delim.len();
+    while i < s.len() {
+        let rest = &s[i..];
+        if let Some(escaped) = rest.strip_prefix('\\') {
+            i += 1 + escaped.chars().next().map_or(0, |c| c.len_utf8());
+        } else if rest.starts_with(delim) {
+            return i + delim.len();
+        } else if rest.starts_with('\n') && delim.len() == 1 && delim != "'" {
+            // unterminated single line string
+            return i;
+        } else {
+            i += rest.chars().next().unwrap().len_utf8();
+        }
+    }
+    s.len()
+}
+
+/// A string with a prefix like `rb"`, as length of the prefix and delimiter
+fn prefixed_string(lang: &Lang, s: &str) -> Option<(usize, &'static str)> {
+    let len = s
+        .find(|c: char| !lang.string_prefixes.contains(c.to_ascii_lowercase()))
+        .unwrap_or(s.len());
+    if len == 0 || len > 2 {
+        return None;
+    }
+    let delim = lang.strings.iter().find(|d| s[len..].starts_with(**d))?;
+    Some((len, delim))
+}
+
+fn tokenize<'a>(lang: &Lang, code: &'a str) -> Vec<Token<'a>> {
+    let mut tokens: Vec<Token<'a>> = vec![];
+    let mut plain_start = 0;
+    let mut i = 0;
+    let mut line_start = true;
+    while i < code.len() {
+        let rest = &code[i..];
+        let c = rest.chars().next().unwrap();
+        let (class, len): (Option<&'static str>, usize) =
+            if let Some(cmt) = lang.line_comments.iter().find(|p| rest.starts_with(**p)) {
+                // shell comments need a word boundary
+                if lang.variables && i > 0 && !code[..i].ends_with(char::is_whitespace) {
+                    (None, cmt.len())
+                } else {
+                    (Some("comment"), rest.find('\n').unwrap_or(rest.len()))
+                }
+            } else if let Some((open, close)) = lang
+                .block_comment
+                .filter(|(open, _)| rest.starts_with(open))
+            {
+                let len = rest[open.len()..]
+                    .find(close)
+                    .map_or(rest.len(), |j| open.len() + j + close.len());
+                (Some("comment"), len)
+            } else if lang.preprocessor && line_start && c == '#' {
+                (Some("meta"), rest.find('\n').unwrap_or(rest.len()))
+            } else if lang.rust_syntax && (rest.starts_with("#[") || rest.starts_with("#![")) {
+                (Some("meta"), rest.find(']').map_or(rest.len(), |j| j + 1))
+            } else if lang.rust_syntax && c == '\'' {
+                // char literal like 'x' or '\'' or lifetime
+                if rest.starts_with("'\\") {
+                    (Some("string"), string_len(rest, "'"))
+                } else {
+                    match rest[1..].char_indices().nth(1) {
+                        Some((j, '\'')) => (Some("string"), j + 2),
+                        _ => (Some("meta"), 1 + ident_len(&rest[1..])),
+                    }
+                }
+            } else if lang.rust_syntax && (rest.starts_with("r#\"") || rest.starts_with("r\"")) {
+                let hashes = &rest[1..rest.find('"').unwrap()];
+                let close = format!("\"{}", hashes);
+                let open = 2 + hashes.len();
+                let len = rest[open..]
+                    .find(&close)
+                    .map_or(rest.len(), |j| open + j + close.len());
+                (Some("string"), len)
+            } else if let Some((prefix, delim)) =
+                prefixed_string(lang, rest).filter(|_| !code[..i].ends_with(is_ident_char))
+            {
+                (Some("string"), prefix + string_len(&rest[prefix..], delim))
+            } else if let Some(delim) = lang
+                .strings
+                .iter()
+                .find(|d| rest.starts_with(**d))
+                // an apostrophe within a word like "don't" starts no string
+                .filter(|d| **d != "'" || !code[..i].ends_with(is_ident_char))
+            {
+                let len = string_len(rest, delim);
+                if lang.keys && rest[len..].trim_start_matches([' ', '\t']).starts_with(':') {
+                    (Some("attr"), len)
+                } else {
+                    (Some("string"), len)
+                }
+            } else if lang.decorators && c == '@' {
+                (Some("meta"), 1 + ident_len(&rest[1..]))
+            } else if lang.variables && c == '$' {
+                if rest.starts_with("${") {
+                    (
+                        Some("variable"),
+                        rest.find('}').map_or(rest.len(), |j| j + 1),
+                    )
+                } else {
+                    let len = match ident_len(&rest[1..]) {
+                        // special parameters like $? or $@
+                        0 if rest[1..].starts_with(['?', '#', '@', '$', '!', '*', '-']) => 1,
+                        n => n,
+                    };
+                    (Some("variable"), 1 + len)
+                }
+            } else if c.is_ascii_digit() {
+                let len = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '.' || c == '_'))
+                    .unwrap_or(rest.len());
+                (Some("number"), len)
+            } else if is_ident_char(c) {
+                let mut len = ident_len(rest);
+                if lang.keys {
+                    // keys may contain dashes and dots
+                    len = rest
+                        .find(|c: char| !(is_ident_char(c) || c == '-' || c == '.'))
+                        .unwrap_or(rest.len());
+                }
+                let word = &rest[..len];
+                if lang.keys && rest[len..].trim_start_matches([' ', '\t']).starts_with(':') {
+                    (Some("attr"), len)
+                } else if lang.keywords.contains(&word) {
+                    (Some("kw"), len)
+                } else if lang.literals.contains(&word) {
+                    (Some("literal"), len)
+                } else if lang.rust_syntax && rest[len..].starts_with('!') {
+                    (Some("macro"), len + 1)
+                } else if lang.upper_types && word.starts_with(|c: char| c.is_uppercase()) {
+                    (Some("type"), len)
+                } else {
+                    (None, len)
+                }
+            } else {
+                (None, c.len_utf8())
+            };
+        if class.is_some() {
+            if plain_start < i {
+                tokens.push(Token {
+                    class: None,
+                    text: &code[plain_start..i],
+                });
+            }
+            tokens.push(Token {
+                class,
+                text: &code[i..i + len],
+            });
+            plain_start = i + len;
+        }
+        line_start = match code[i..i + len].chars().last() {
+            Some('\n') => true,
+            Some(c) if c.is_whitespace() => line_start,
+            _ => false,
+        };
+        i += len;
+    }
+    if plain_start < code.len() {
+        tokens.push(Token {
+            class: None,
+            text: &code[plain_start..],
+        });
+    }
+    tokens
+}
+
+/// Line numbers to highlight like `2-4,7`
+pub fn parse_line_ranges(spec: &str) -> Vec<(usize, usize)> {
+    let mut ret = vec![];
+    for part in spec.split(',') {
+        let part = part.trim();
+        let range = match part.split_once('-') {
+            Some((a, b)) => (a.trim().parse(), b.trim().parse()),
+            None => (part.parse(), part.parse()),
+        };
+        match range {
+            (Ok(a), Ok(b)) => ret.push((a, b)),
+            _ => log::warn!("Invalid line range: {}", part),
+        }
+    }
+    ret
+}
+
+/// Render code as HTML, highlighted if the language is known.
+/// With line numbers or highlighted lines, every line is a span.
+pub fn render(lang: &str, code: &str, linenos: bool, marked: &[(usize, usize)]) -> String {
+    let tokens = match lang_by_name(lang) {
+        Some(l) => tokenize(l, code),
+        None => vec![Token {
+            class: None,
+            text: code,
+        }],
+    };
+    let by_line = linenos || !marked.is_empty();
+    let line_count = code.lines().count();
+    let mut html = String::with_capacity(code.len() * 2);
+    let mut line = 0;
+    let start_line = |html: &mut String, line: usize| {
+        if !by_line {
+            return;
+        }
+        if marked.iter().any(|(a, b)| *a <= line && line <= *b) {
+            html.push_str(r#"<span class="line hl">"#);
+        } else {
+            html.push_str(r#"<span class="line">"#);
+        }
+        if linenos {
+            html.push_str(&format!(r#"<span class="lineno">{}</span>"#, line));
+        }
+    };
+    if !code.is_empty() {
+        line += 1;
+        start_line(&mut html, line);
+    }
+    for t in tokens {
+        let mut parts = t.text.split('\n').peekable();
+        while let Some(part) = parts.next() {
+            if !part.is_empty() {
+                if let Some(class) = t.class {
+                    html.push_str(&format!(r#"<span class="hl-{}">"#, class));
+                    escape_html(&mut html, part).unwrap();
+                    html.push_str("</span>");
+                } else {
+                    escape_html(&mut html, part).unwrap();
+                }
+            }
+            if parts.peek().is_some() {
+                // a token spanning lines is closed and reopened per line
+                if by_line {
+                    html.push_str("</span>");
+                }
+                html.push('\n');
+                if by_line && line < line_count {
+                    line += 1;
+                    start_line(&mut html, line);
+                }
+            }
+        }
+    }
+    if by_line && !code.ends_with('\n') && !code.is_empty() {
+        html.push_str("</span>");
+    }
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The highlighted tokens as class and text
+    fn spans(lang: &str, code: &str) -> Vec<(&'static str, String)> {
+        tokenize(lang_by_name(lang).unwrap(), code)
+            .into_iter()
+            .filter_map(|t| Some((t.class?, t.text.to_string())))
+            .collect()
+    }
+
+    fn span(class: &'static str, text: &str) -> (&'static str, String) {
+        (class, text.to_string())
+    }
+
+    #[test]
+    fn rust_chars_and_lifetimes() {
+        assert_eq!(
+            spans("rust", r"let c = '\''; x"),
+            [span("kw", "let"), span("string", r"'\''")]
+        );
+        assert_eq!(
+            spans("rust", r"('a', '\n', 'é')"),
+            [
+                span("string", "'a'"),
+                span("string", r"'\n'"),
+                span("string", "'é'")
+            ]
+        );
+        assert_eq!(
+            spans("rust", "fn f<'a>(x: &'a str)"),
+            [span("kw", "fn"), span("meta", "'a"), span("meta", "'a")]
+        );
+    }
+
+    #[test]
+    fn rust_strings_and_comments() {
+        assert_eq!(
+            spans("rust", r#"s("a\"b") // c"#),
+            [span("string", r#""a\"b""#), span("comment", "// c")]
+        );
+        assert_eq!(
+            spans("rust", r###"r#"a"b"# /* c */ x!()"###),
+            [
+                span("string", r###"r#"a"b"#"###),
+                span("comment", "/* c */"),
+                span("macro", "x!")
+            ]
+        );
+    }
+
+    #[test]
+    fn c_and_cpp() {
+        assert_eq!(
+            spans("c", "#include <x.h>\nchar c = '\\''; // c"),
+            [
+                span("meta", "#include <x.h>"),
+                span("kw", "char"),
+                span("string", r"'\''"),
+                span("comment", "// c")
+            ]
+        );
+        assert_eq!(
+            spans("cpp", r#"auto s = "a\\"; /* "no" */"#),
+            [
+                span("kw", "auto"),
+                span("string", r#""a\\""#),
+                span("comment", r#"/* "no" */"#)
+            ]
+        );
+    }
+
+    #[test]
+    fn python_strings() {
+        assert_eq!(
+            spans("python", r#"f"{x}" + rb'\x00' + u'a' # c"#),
+            [
+                span("string", r#"f"{x}""#),
+                span("string", r"rb'\x00'"),
+                span("string", "u'a'"),
+                span("comment", "# c")
+            ]
+        );
+        assert_eq!(
+            spans("python", "'''a\n'b'\n''' if"),
+            [span("string", "'''a\n'b'\n'''"), span("kw", "if")]
+        );
+        // only a prefix of one or two letters starts a string
+        assert_eq!(
+            spans("python", r#"if f"x" or buf"y""#),
+            [
+                span("kw", "if"),
+                span("string", r#"f"x""#),
+                span("kw", "or"),
+                span("string", r#""y""#)
+            ]
+        );
+    }
+
+    #[test]
+    fn json_yaml_and_shell() {
+        assert_eq!(
+            spans("json", r#"{"k\"": "v", "n": null}"#),
+            [
+                span("attr", r#""k\"""#),
+                span("string", r#""v""#),
+                span("attr", r#""n""#),
+                span("literal", "null")
+            ]
+        );
+        assert_eq!(
+            spans("yaml", "key: 'a # b' # c"),
+            [
+                span("attr", "key"),
+                span("string", "'a # b'"),
+                span("comment", "# c")
+            ]
+        );
+        assert_eq!(
+            spans("sh", r#"echo "$x" a#b $? # c"#),
+            [
+                span("string", r#""$x""#),
+                span("variable", "$?"),
+                span("comment", "# c")
+            ]
+        );
+    }
+}
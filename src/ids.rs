@@ -0,0 +1,295 @@
+//! Stable DIDs for documents without an explicit id
+
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::fs;
+
+use crate::config::Config;
+use crate::gen_html::{read_all_md_files, Doc};
+use crate::headings;
+use crate::rename;
+use crate::vars;
+
+/// Whether the DID is derived from path and title instead of an `id:` line
+fn has_hash_did(d: &Doc) -> bool {
+    !vars::doc_vars(&d.raw).contains_key("id")
+}
+
+/// Warn about documents whose DID changes when they are moved or renamed
+pub fn check_hash_dids(docs: &[Doc]) {
+    let n = docs.iter().filter(|d| has_hash_did(d)).count();
+    for d in docs.iter().filter(|d| has_hash_did(d)) {
+        info!("No id: {}", d.src_path_rel.display());
+    }
+    if n > 0 {
+        warn!(
+            "{} documents have no id and their DID changes with path or title, see assign-ids",
+            n
+        );
+    }
+}
+
+/// Insert an id line into the doc meta info or append doc meta info
+pub fn insert_id(raw: &str, did: &str) -> String {
+    let line = format!("id: {}\n", did);
+    let mut ret = String::with_capacity(raw.len() + line.len());
+    let mut inserted = false;
+    for l in raw.split_inclusive('\n') {
+        ret.push_str(l);
+        if !inserted && l.trim_end() == "```docdustry-docmeta" {
+            ret.push_str(&line);
+            inserted = true;
+        }
+    }
+    if !inserted {
+        if !ret.is_empty() && !ret.ends_with('\n') {
+            ret.push('\n');
+        }
+        ret.push_str("\n```docdustry-docmeta\n");
+        ret.push_str(&line);
+        ret.push_str("```\n");
+    }
+    ret
+}
+
+/// Generates sequential DIDs like `REQ-0042`
+pub struct IdCounter {
+    /// highest number used so far per prefix
+    used: HashMap<String, usize>,
+}
+
+impl IdCounter {
+    pub fn new(docs: &[Doc]) -> IdCounter {
+        let mut used: HashMap<String, usize> = HashMap::new();
+        for d in docs {
+            if let Some((prefix, num)) = d.did.rsplit_once('-') {
+                if let Ok(n) = num.parse::<usize>() {
+                    let e = used.entry(prefix.to_string()).or_default();
+                    *e = (*e).max(n);
+                }
+            }
+        }
+        IdCounter { used }
+    }
+
+    pub fn next(&mut self, prefix: &str) -> String {
+        let n = self.used.entry(prefix.to_string()).or_default();
+        *n += 1;
+        format!("{}-{:04}", prefix, n)
+    }
+}
+
+pub(crate) fn cmd_assign_ids(cfg: &Config, dry_run: bool) {
+    let mut docs: Vec<Doc> = vec![];
+    for src in cfg.get_sources() {
+        // the numbers are unique across variants
+        read_all_md_files(&mut docs, src.as_path(), cfg);
+    }
+    let mut counter = IdCounter::new(&docs);
+    // in path order, so numbers follow the file structure
+    let mut todo: Vec<&Doc> = docs.iter().filter(|d| has_hash_did(d)).collect();
+    todo.sort_by(|a, b| a.src_path_rel.cmp(&b.src_path_rel));
+    // the derived and the new DID of each document
+    let mut renames: Vec<(&str, String)> = vec![];
+    for d in todo {
+        let did = counter.next(&cfg.id_prefix(&d.doc_type));
+        println!("{}: {}", d.src_path_rel.display(), did);
+        renames.push((&d.did, did));
+    }
+    // insert the ids and update the links to the derived DIDs
+    for d in &docs {
+        let mut raw = d.raw.clone();
+        let mut linked = vec![];
+        for (old, new) in &renames {
+            if d.did != *old && !raw.contains(old) {
+                continue;
+            }
+            let renamed = rename::rename_in(&raw, &d.did, old, new);
+            if d.did != *old && renamed != raw {
+                linked.push(*old);
+            }
+            raw = renamed;
+        }
+        if raw == d.raw {
+            continue;
+        }
+        if !linked.is_empty() {
+            println!(
+                "{}: links to {}",
+                d.src_path_rel.display(),
+                linked.join(", ")
+            );
+        }
+        if dry_run {
+            continue;
+        }
+        let path = d.src_path();
+        if let Err(e) = fs::write(&path, raw) {
+            warn!("Failed to write {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Content of a new document, from the template if there is one
+fn new_doc_content(cfg: &Config, doc_type: &str, did: &str, title: &str) -> String {
+    let t = cfg.types.get(doc_type);
+    let status = t
+        .and_then(|t| t.status.clone())
+        .or_else(|| Some(cfg.lifecycle(doc_type)?.statuses.first()?.clone()))
+        .unwrap_or_default();
+    let template = t.and_then(|t| t.template.as_ref()).and_then(|p| {
+        fs::read_to_string(p)
+            .map_err(|e| warn!("Failed to read template {}: {}", p.display(), e))
+            .ok()
+    });
+    match template {
+        Some(tmpl) => {
+            let raw = tmpl
+                .replace("{{id}}", did)
+                .replace("{{title}}", title)
+                .replace("{{type}}", doc_type)
+                .replace("{{status}}", &status);
+            if vars::doc_vars(&raw).contains_key("id") {
+                raw
+            } else {
+                insert_id(&raw, did)
+            }
+        }
+        None => {
+            let mut raw = format!(
+                "# {}\n\n```docdustry-docmeta\nid: {}\ntype: {}\n",
+                title, did, doc_type
+            );
+            if !status.is_empty() {
+                raw.push_str(&format!("status: {}\n", status));
+            }
+            raw.push_str("```\n");
+            raw
+        }
+    }
+}
+
+pub(crate) fn cmd_new(cfg: &Config, doc_type: &str, title: Option<String>) {
+    if !cfg.types.contains_key(doc_type) {
+        warn!("Document type not in the config: {}", doc_type);
+    }
+    let mut docs: Vec<Doc> = vec![];
+    for src in cfg.get_sources() {
+        // new documents are rarely in the variant yet
+        read_all_md_files(&mut docs, src.as_path(), cfg);
+    }
+    let did = IdCounter::new(&docs).next(&cfg.id_prefix(doc_type));
+    let title = title.unwrap_or_else(|| did.clone());
+    let dir = match cfg.types.get(doc_type).and_then(|t| t.dir.clone()) {
+        Some(d) => d,
+        None => cfg.get_sources()[0].clone(),
+    };
+    let path = dir.join(format!("{}.md", headings::slugify(&title)));
+    if path.exists() {
+        error!("File exists already: {}", path.display());
+        return;
+    }
+    if let Err(e) = fs::create_dir_all(&dir)
+        .and_then(|_| fs::write(&path, new_doc_content(cfg, doc_type, &did, &title)))
+    {
+        error!("Failed to write {}: {}", path.display(), e);
+        return;
+    }
+    println!("{}: {}", path.display(), did);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DocType;
+
+    #[test]
+    fn inserting_ids() {
+        assert_eq!(
+            insert_id("# A\n\n```docdustry-docmeta\nstatus: draft\n```\n", "A-1"),
+            "# A\n\n```docdustry-docmeta\nid: A-1\nstatus: draft\n```\n"
+        );
+        assert_eq!(
+            insert_id("# A\n\nText", "A-1"),
+            "# A\n\nText\n\n```docdustry-docmeta\nid: A-1\n```\n"
+        );
+    }
+
+    #[test]
+    fn assigning_ids() {
+        let dir = std::env::temp_dir().join(format!("docdustry-assign-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, raw: &str| fs::write(dir.join(name), raw).unwrap();
+        write(
+            "a.md",
+            "# A\n\n```docdustry-docmeta\ntype: requirement\n```\n",
+        );
+        write(
+            "b.md",
+            "# B\n\n```docdustry-docmeta\nid: REQ-0007\ntype: requirement\n```\n",
+        );
+        write("c.md", "# C\n\n```docdustry-docmeta\ntype: test\n```\n");
+        write("d.md", "# D\n");
+        let mut cfg = Config::new();
+        cfg.push_source_dir(dir.clone());
+        for (name, prefix) in [("requirement", "REQ"), ("test", "TST")] {
+            let mut t = DocType::new();
+            t.prefix = Some(prefix.to_string());
+            cfg.types.insert(name.to_string(), t);
+        }
+        let mut docs = vec![];
+        read_all_md_files(&mut docs, &dir, &cfg);
+        let old_a = docs
+            .iter()
+            .find(|d| d.raw.starts_with("# A"))
+            .unwrap()
+            .did
+            .clone();
+        write("e.md", &format!("# E\n\nSee [A](did:{}#x).\n", old_a));
+
+        cmd_assign_ids(&cfg, false);
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        let id = |name: &str| vars::doc_vars(&read(name)).get("id").cloned();
+        let ids = [id("a.md"), id("b.md"), id("c.md"), id("d.md")];
+        let e = read("e.md");
+        fs::remove_dir_all(&dir).unwrap();
+        // numbered per prefix, after the highest number in use
+        assert_eq!(
+            ids.map(|i| i.unwrap()),
+            ["REQ-0008", "REQ-0007", "TST-0001", "DOC-0001"]
+        );
+        assert_eq!(
+            e,
+            "# E\n\nSee [A](did:REQ-0008#x).\n\n```docdustry-docmeta\nid: DOC-0002\n```\n"
+        );
+    }
+
+    #[test]
+    fn new_ids_across_variants() {
+        let dir = std::env::temp_dir().join(format!("docdustry-ids-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("old.md"),
+            "# Old\n\n```docdustry-docmeta\nid: REQ-0001\ntag: lite\n```\n",
+        )
+        .unwrap();
+        let mut cfg = Config::new();
+        cfg.push_source_dir(dir.clone());
+        cfg.set_variant("pro");
+        let mut t = DocType::new();
+        t.prefix = Some("REQ".to_string());
+        t.dir = Some(dir.clone());
+        cfg.types.insert("requirement".to_string(), t);
+
+        cmd_new(&cfg, "requirement", Some("First".to_string()));
+        cmd_new(&cfg, "requirement", Some("Second".to_string()));
+        let id = |name: &str| {
+            let raw = fs::read_to_string(dir.join(name)).unwrap();
+            vars::doc_vars(&raw)["id"].clone()
+        };
+        let (first, second) = (id("first.md"), id("second.md"));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(first, "REQ-0002");
+        assert_eq!(second, "REQ-0003");
+    }
+}
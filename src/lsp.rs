@@ -0,0 +1,513 @@
+//! Language server for editors, speaking LSP over stdio
+//!
+//! The index of documents is the same as for generating
+//! and gets refreshed whenever a file is saved.
+//! Files open in the editor are used with their unsaved text.
+
+use log::{info, warn};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::gen_html::{read_all_md_files, Doc};
+use crate::glossary::GLOSSARY_DID;
+use crate::rename::{all_did_refs, did_refs, is_did_char, rename_in, DidRef};
+use crate::vars;
+
+const ERROR: u8 = 1;
+const WARNING: u8 = 2;
+const INFORMATION: u8 = 3;
+
+/// JSON-RPC error codes
+const METHOD_NOT_FOUND: i64 = -32601;
+const REQUEST_FAILED: i64 = -32803;
+
+/// A Content-Length framed message, None at the end of input
+fn read_message(input: &mut impl BufRead) -> Option<Value> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((k, v)) = line.split_once(':') {
+            if k.eq_ignore_ascii_case("content-length") {
+                len = v.trim().parse().ok();
+            }
+        }
+    }
+    let mut buf = vec![0; len?];
+    input.read_exact(&mut buf).ok()?;
+    match serde_json::from_slice(&buf) {
+        Ok(msg) => Some(msg),
+        Err(e) => {
+            warn!("Invalid message: {}", e);
+            Some(Value::Null)
+        }
+    }
+}
+
+fn send(msg: &Value) {
+    let body = msg.to_string();
+    let mut out = io::stdout().lock();
+    if let Err(e) = write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body) {
+        warn!("Failed to send: {}", e);
+    }
+    let _ = out.flush();
+}
+
+fn canonical(p: &Path) -> PathBuf {
+    fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf())
+}
+
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = vec![];
+    let mut i = 0;
+    while i < encoded.len() {
+        if encoded[i] == b'%' {
+            let hex = encoded
+                .get(i + 1..i + 3)
+                .and_then(|h| std::str::from_utf8(h).ok());
+            if let Some(b) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                bytes.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        bytes.push(encoded[i]);
+        i += 1;
+    }
+    Some(canonical(Path::new(&*String::from_utf8_lossy(&bytes))))
+}
+
+fn path_to_uri(p: &Path) -> String {
+    let mut ret = "file://".to_string();
+    for b in p.to_string_lossy().bytes() {
+        if b.is_ascii_alphanumeric() || b"/-._~".contains(&b) {
+            ret.push(b as char);
+        } else {
+            ret.push_str(&format!("%{:02X}", b));
+        }
+    }
+    ret
+}
+
+/// LSP position of a byte offset, counting UTF-16 code units
+fn position(text: &str, offset: usize) -> Value {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    json!({
+        "line": before.matches('\n').count(),
+        "character": before[line_start..].encode_utf16().count(),
+    })
+}
+
+fn range(text: &str, r: &Range<usize>) -> Value {
+    json!({"start": position(text, r.start), "end": position(text, r.end)})
+}
+
+/// Byte offset of an LSP position
+fn offset(text: &str, pos: &Value) -> usize {
+    let line = pos["line"].as_u64().unwrap_or(0);
+    let character = pos["character"].as_u64().unwrap_or(0) as usize;
+    let mut start = 0;
+    for _ in 0..line {
+        match text[start..].find('\n') {
+            Some(i) => start += i + 1,
+            None => return text.len(),
+        }
+    }
+    let mut units = 0;
+    for (i, c) in text[start..].char_indices() {
+        if units >= character || c == '\n' {
+            return start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+/// The DID at a byte offset, including the offset right after it
+fn did_at(text: &str, offset: usize) -> Option<DidRef> {
+    all_did_refs(text)
+        .into_iter()
+        .find(|r| r.range.start <= offset && offset <= r.range.end)
+}
+
+/// A line of the doc meta info, with `key: value` if it has it
+type MetaLine<'a> = (Range<usize>, Option<(&'a str, &'a str)>);
+
+fn meta_lines(text: &str) -> Vec<MetaLine<'_>> {
+    let mut ret = vec![];
+    let mut in_meta = false;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+        let line = line.trim_end();
+        if in_meta {
+            if line.starts_with("```") {
+                in_meta = false;
+            } else if !line.trim().is_empty() {
+                let kv = line.split_once(':').map(|(k, v)| (k.trim(), v.trim()));
+                ret.push((start..start + line.len(), kv));
+            }
+        } else if line == "```docdustry-docmeta" {
+            in_meta = true;
+        }
+    }
+    ret
+}
+
+fn describe(d: &Doc) -> String {
+    let mut ret = format!("**{}**\n\n`{}`", d.title, d.did);
+    for field in [&d.doc_type, &d.status] {
+        if !field.is_empty() {
+            ret.push_str(" · ");
+            ret.push_str(field);
+        }
+    }
+    ret.push_str(&format!("\n\n{}", d.src_path_rel.display()));
+    ret
+}
+
+struct Server<'a> {
+    cfg: &'a Config,
+    docs: Vec<Doc>,
+    /// canonical paths of the documents in the same order
+    paths: Vec<PathBuf>,
+    /// text of the files open in the editor
+    open: HashMap<PathBuf, String>,
+}
+
+impl Server<'_> {
+    fn reindex(&mut self) {
+        self.docs.clear();
+        for src in self.cfg.get_sources() {
+            read_all_md_files(&mut self.docs, src.as_path(), self.cfg);
+        }
+        self.paths = self.docs.iter().map(|d| canonical(&d.src_path())).collect();
+        info!("Indexed {} documents", self.docs.len());
+    }
+
+    fn doc(&self, did: &str) -> Option<&Doc> {
+        self.docs.iter().find(|d| d.did == did)
+    }
+
+    fn known(&self, did: &str) -> bool {
+        self.doc(did).is_some()
+            || (did == GLOSSARY_DID && self.docs.iter().any(|d| !d.glossary.is_empty()))
+    }
+
+    fn text(&self, path: &Path) -> Option<String> {
+        if let Some(t) = self.open.get(path) {
+            return Some(t.clone());
+        }
+        let i = self.paths.iter().position(|p| p == path)?;
+        Some(self.docs[i].raw.clone())
+    }
+
+    /// Text of all documents and open files
+    fn all_texts(&self) -> Vec<(PathBuf, String)> {
+        let mut ret: Vec<(PathBuf, String)> = self
+            .paths
+            .iter()
+            .filter_map(|p| Some((p.clone(), self.text(p)?)))
+            .collect();
+        for (p, t) in &self.open {
+            if !self.paths.contains(p) {
+                ret.push((p.clone(), t.clone()));
+            }
+        }
+        ret
+    }
+
+    /// Path, text, and byte offset of the position in the params
+    fn locate(&self, params: &Value) -> Result<(PathBuf, String, usize), String> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        let path = uri_to_path(uri).ok_or(format!("Not a file: {}", uri))?;
+        let text = self.text(&path).ok_or(format!("Unknown file: {}", uri))?;
+        let offset = offset(&text, &params["position"]);
+        Ok((path, text, offset))
+    }
+
+    fn diagnostics(&self, path: &Path, text: &str) -> Vec<Value> {
+        let diag = |r: &Range<usize>, severity: u8, message: String| {
+            json!({
+                "range": range(text, r),
+                "severity": severity,
+                "source": "docdustry",
+                "message": message,
+            })
+        };
+        let mut ret = vec![];
+        for r in all_did_refs(text) {
+            if r.is_id {
+                let other = self.docs.iter().zip(&self.paths);
+                if let Some((d, _)) = other
+                    .filter(|(_, p)| *p != path)
+                    .find(|(d, _)| d.did == r.did)
+                {
+                    let msg = format!("DID also used by {}", d.src_path_rel.display());
+                    ret.push(diag(&r.range, ERROR, msg));
+                }
+            } else if !self.known(&r.did) {
+                ret.push(diag(&r.range, WARNING, format!("Unknown DID: {}", r.did)));
+            }
+        }
+        let meta = vars::doc_vars(text);
+        if !meta.contains_key("id") {
+            let msg = "No id, the DID changes with path or title, see assign-ids".to_string();
+            ret.push(diag(&(0..0), INFORMATION, msg));
+        }
+        let doc_type = meta.get("type").map_or("", |t| t.as_str());
+        for (r, kv) in meta_lines(text) {
+            match kv {
+                None => ret.push(diag(&r, WARNING, "Ignored, not `key: value`".to_string())),
+                Some(("toc-depth", v)) if v.parse::<usize>().is_err() => {
+                    ret.push(diag(&r, ERROR, format!("Invalid toc-depth: {}", v)))
+                }
+                Some(("status", v)) => match self.cfg.lifecycle(doc_type) {
+                    Some(lc) if !v.is_empty() && !lc.statuses.iter().any(|s| s == v) => {
+                        let msg = format!(
+                            "Unknown status: {} (expected one of {})",
+                            v,
+                            lc.statuses.join(", ")
+                        );
+                        ret.push(diag(&r, WARNING, msg));
+                    }
+                    _ => (),
+                },
+                _ => (),
+            }
+        }
+        ret
+    }
+
+    fn publish_diagnostics(&self, path: &Path) {
+        let diagnostics = match self.open.get(path) {
+            Some(text) => self.diagnostics(path, text),
+            None => vec![],
+        };
+        send(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": {"uri": path_to_uri(path), "diagnostics": diagnostics},
+        }));
+    }
+
+    fn completion(&self, params: &Value) -> Result<Value, String> {
+        let (_, text, offset) = self.locate(params)?;
+        let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let before = text[line_start..offset].trim_end_matches(is_did_char);
+        if !before.ends_with("did:") {
+            return Ok(json!([]));
+        }
+        let r = line_start + before.len()..offset;
+        let items: Vec<Value> = self
+            .docs
+            .iter()
+            .map(|d| {
+                json!({
+                    "label": d.did,
+                    "kind": 18,
+                    "detail": d.title,
+                    "documentation": {"kind": "markdown", "value": describe(d)},
+                    "textEdit": {"range": range(&text, &r), "newText": d.did},
+                })
+            })
+            .collect();
+        Ok(json!(items))
+    }
+
+    fn hover(&self, params: &Value) -> Result<Value, String> {
+        let (_, text, offset) = self.locate(params)?;
+        let Some(r) = did_at(&text, offset) else {
+            return Ok(Value::Null);
+        };
+        let value = match self.doc(&r.did) {
+            Some(d) => describe(d),
+            None if self.known(&r.did) => "**Glossary**".to_string(),
+            None => format!("Unknown DID `{}`", r.did),
+        };
+        Ok(json!({
+            "contents": {"kind": "markdown", "value": value},
+            "range": range(&text, &r.range),
+        }))
+    }
+
+    fn definition(&self, params: &Value) -> Result<Value, String> {
+        let (_, text, offset) = self.locate(params)?;
+        let Some(r) = did_at(&text, offset) else {
+            return Ok(Value::Null);
+        };
+        match self.docs.iter().position(|d| d.did == r.did) {
+            Some(i) => Ok(json!({
+                "uri": path_to_uri(&self.paths[i]),
+                "range": range("", &(0..0)),
+            })),
+            None => Ok(Value::Null),
+        }
+    }
+
+    fn references(&self, params: &Value) -> Result<Value, String> {
+        let (_, text, offset) = self.locate(params)?;
+        let Some(target) = did_at(&text, offset) else {
+            return Ok(Value::Null);
+        };
+        let declaration = params["context"]["includeDeclaration"]
+            .as_bool()
+            .unwrap_or(true);
+        let mut ret = vec![];
+        for (path, text) in self.all_texts() {
+            for r in all_did_refs(&text) {
+                if r.did == target.did && (declaration || !r.is_id) {
+                    ret.push(json!({"uri": path_to_uri(&path), "range": range(&text, &r.range)}));
+                }
+            }
+        }
+        Ok(json!(ret))
+    }
+
+    fn rename(&self, params: &Value) -> Result<Value, String> {
+        let (_, text, offset) = self.locate(params)?;
+        let old = did_at(&text, offset).ok_or("No DID at the cursor")?.did;
+        let new = params["newName"].as_str().unwrap_or("");
+        if new.is_empty() || !new.chars().all(is_did_char) {
+            return Err(format!("Invalid DID: {}", new));
+        }
+        if self.doc(new).is_some() {
+            return Err(format!("DID exists already: {}", new));
+        }
+        let mut changes = Map::new();
+        for (path, text) in self.all_texts() {
+            let doc_did = match self.paths.iter().position(|p| *p == path) {
+                Some(i) => self.docs[i].did.as_str(),
+                None => "",
+            };
+            let edits: Vec<Value> = if doc_did == old && !vars::doc_vars(&text).contains_key("id") {
+                // the DID was derived from path and title, so an id gets inserted
+                let new_text = rename_in(&text, doc_did, &old, new);
+                vec![json!({"range": range(&text, &(0..text.len())), "newText": new_text})]
+            } else {
+                did_refs(&text, &old)
+                    .iter()
+                    .map(|r| json!({"range": range(&text, r), "newText": new}))
+                    .collect()
+            };
+            if !edits.is_empty() {
+                changes.insert(path_to_uri(&path), json!(edits));
+            }
+        }
+        Ok(json!({"changes": changes}))
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        let Some(path) = uri_to_path(uri) else {
+            return;
+        };
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                self.open.insert(path.clone(), text.to_string());
+                self.publish_diagnostics(&path);
+            }
+            "textDocument/didChange" => {
+                // full text with each change
+                if let Some(change) = params["contentChanges"].as_array().and_then(|c| c.last()) {
+                    let text = change["text"].as_str().unwrap_or("");
+                    self.open.insert(path.clone(), text.to_string());
+                }
+                self.publish_diagnostics(&path);
+            }
+            "textDocument/didSave" => {
+                self.reindex();
+                for p in self.open.keys() {
+                    self.publish_diagnostics(p);
+                }
+            }
+            "textDocument/didClose" => {
+                self.open.remove(&path);
+                self.publish_diagnostics(&path);
+            }
+            _ => (),
+        }
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "capabilities": {
+            "textDocumentSync": {"openClose": true, "change": 1, "save": true},
+            "completionProvider": {"triggerCharacters": [":"]},
+            "hoverProvider": true,
+            "definitionProvider": true,
+            "referencesProvider": true,
+            "renameProvider": true,
+        },
+        "serverInfo": {"name": "docdustry"},
+    })
+}
+
+pub(crate) fn cmd_lsp(cfg: &Config) {
+    let mut server = Server {
+        cfg,
+        docs: vec![],
+        paths: vec![],
+        open: HashMap::new(),
+    };
+    server.reindex();
+    let mut shutdown = false;
+    let mut input = io::stdin().lock();
+    while let Some(msg) = read_message(&mut input) {
+        let Some(method) = msg["method"].as_str() else {
+            continue;
+        };
+        let params = &msg["params"];
+        let Some(id) = msg.get("id") else {
+            if method == "exit" {
+                std::process::exit(if shutdown { 0 } else { 1 });
+            }
+            server.notification(method, params);
+            continue;
+        };
+        let result = match method {
+            "initialize" => Ok(capabilities()),
+            "shutdown" => {
+                shutdown = true;
+                Ok(Value::Null)
+            }
+            "textDocument/completion" => server.completion(params),
+            "textDocument/hover" => server.hover(params),
+            "textDocument/definition" => server.definition(params),
+            "textDocument/references" => server.references(params),
+            "textDocument/rename" => server.rename(params),
+            _ => {
+                send(&json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": {"code": METHOD_NOT_FOUND, "message": format!("Unknown method: {}", method)},
+                }));
+                continue;
+            }
+        };
+        send(&match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err(message) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": REQUEST_FAILED, "message": message},
+            }),
+        });
+    }
+}
@@ -3,11 +3,24 @@ use ini::Ini;
 use log::{info, warn};
 use std::path::{Path, PathBuf};
 
+mod conditions;
 mod config;
+mod fmt;
 mod gen_db;
 mod gen_files;
 mod gen_html;
+mod glossary;
+mod headings;
+mod highlight;
+mod ids;
+mod lsp;
+mod md_lines;
+mod query;
+mod query_cmd;
+mod rename;
 mod spam_md;
+mod status;
+mod vars;
 
 #[derive(Parser)]
 struct Cli {
@@ -18,6 +31,10 @@ struct Cli {
     /// Config file in ini format
     #[arg(short = 'i', long = "ini")]
     ini: Option<PathBuf>,
+
+    /// Tags of the variant to build like `pro,-lite`, overriding the config
+    #[arg(long = "variant", global = true)]
+    variant: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -25,7 +42,70 @@ enum Command {
     /// Generate HTML documentation
     Gen {},
     /// Generate Sqlite3 table
-    GenDB {},
+    GenDB {
+        /// Record the documents as a snapshot for diff
+        #[arg(long)]
+        snapshot: bool,
+    },
+    /// Print documents matching a filter or the result of SQL
+    Query {
+        /// Filter like `status = accepted and tag = safety`
+        filter: Option<String>,
+        /// SQL against the database of gen-db instead of a filter
+        #[arg(long)]
+        sql: Option<String>,
+        /// table, json, csv, or dids
+        #[arg(long, default_value = "table")]
+        format: String,
+        /// Fields to print, comma separated
+        #[arg(long)]
+        columns: Option<String>,
+        /// Fields to sort by, descending with a - prefix
+        #[arg(long)]
+        sort_by: Option<String>,
+        /// Print at most this many documents
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Create a document of a type
+    New {
+        /// Document type like `requirement`
+        doc_type: String,
+        #[arg(long)]
+        title: Option<String>,
+    },
+    /// Write generated DIDs into documents without id
+    AssignIds {
+        /// Only print the new DIDs
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Change a DID and all references to it in the sources
+    RenameDid {
+        old: String,
+        new: String,
+        /// Only print the changes
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// List changed documents between two snapshots
+    Diff {
+        /// Snapshot id
+        from: i64,
+        /// Snapshot id
+        to: i64,
+    },
+    /// Normalize the notation of the Markdown sources
+    Fmt {
+        /// Only list unformatted files and fail if there are any
+        #[arg(long)]
+        check: bool,
+        /// Wrap paragraphs at this width
+        #[arg(long)]
+        wrap: Option<usize>,
+    },
+    /// Serve the Language Server Protocol over stdio for editors
+    Lsp {},
     /// Generate random spam
     SpamMd {},
 }
@@ -51,11 +131,70 @@ fn main() {
                                 cfg.frontpage = Some(v.to_string());
                             } else if k == "theme" {
                                 cfg.theme = Some(PathBuf::from(v.to_string()));
+                            } else if k == "inline-svg" {
+                                cfg.inline_svg = v == "true";
                             } else {
                                 warn!("Unknown config [gen] {}:{}", k, v);
                             }
                         }
                     }
+                    Some("status") => {
+                        for (k, v) in prop.iter() {
+                            if k == "released" {
+                                cfg.released = split_list(v);
+                            } else if k == "inactive" {
+                                cfg.inactive = split_list(v);
+                            } else if k == "inactive-mode" {
+                                cfg.inactive_mode = match v {
+                                    "exclude" => config::InactiveMode::Exclude,
+                                    "watermark" => config::InactiveMode::Watermark,
+                                    _ => {
+                                        warn!("Unknown config [status] {}:{}", k, v);
+                                        config::InactiveMode::Watermark
+                                    }
+                                };
+                            } else {
+                                warn!("Unknown config [status] {}:{}", k, v);
+                            }
+                        }
+                    }
+                    Some("vars") => {
+                        for (k, v) in prop.iter() {
+                            cfg.vars.insert(k.to_string(), v.to_string());
+                        }
+                    }
+                    Some("variant") => {
+                        for (k, v) in prop.iter() {
+                            if k == "include" {
+                                cfg.variant_include = split_list(v);
+                            } else if k == "exclude" {
+                                cfg.variant_exclude = split_list(v);
+                            } else {
+                                warn!("Unknown config [variant] {}:{}", k, v);
+                            }
+                        }
+                    }
+                    Some(s) if s.starts_with("type.") => {
+                        let t = cfg
+                            .types
+                            .entry(s["type.".len()..].to_string())
+                            .or_insert_with(config::DocType::new);
+                        for (k, v) in prop.iter() {
+                            if k == "lifecycle" {
+                                t.lifecycle = Some(config::Lifecycle::parse(v));
+                            } else if k == "prefix" {
+                                t.prefix = Some(v.to_string());
+                            } else if k == "dir" {
+                                t.dir = Some(PathBuf::from(v));
+                            } else if k == "template" {
+                                t.template = Some(PathBuf::from(v));
+                            } else if k == "status" {
+                                t.status = Some(v.to_string());
+                            } else {
+                                warn!("Unknown config [{}] {}:{}", s, k, v);
+                            }
+                        }
+                    }
                     Some(_) => (),
                     None => (),
                 }
@@ -67,9 +206,47 @@ fn main() {
         info!("No config file given.");
     }
 
+    if let Some(variant) = args.variant {
+        cfg.set_variant(&variant);
+    }
+
     match args.command {
         Command::Gen {} => gen_files::cmd_gen(&cfg),
-        Command::GenDB {} => gen_db::cmd_gen_db(&cfg),
+        Command::GenDB { snapshot } => gen_db::cmd_gen_db(&cfg, snapshot),
+        Command::Query {
+            filter,
+            sql,
+            format,
+            columns,
+            sort_by,
+            limit,
+        } => query_cmd::cmd_query(
+            &cfg,
+            &query_cmd::QueryArgs {
+                filter,
+                sql,
+                format,
+                columns,
+                sort_by,
+                limit,
+            },
+        ),
+        Command::New { doc_type, title } => ids::cmd_new(&cfg, &doc_type, title),
+        Command::AssignIds { dry_run } => ids::cmd_assign_ids(&cfg, dry_run),
+        Command::RenameDid { old, new, dry_run } => {
+            rename::cmd_rename_did(&cfg, &old, &new, dry_run)
+        }
+        Command::Diff { from, to } => gen_db::cmd_diff(&cfg, from, to),
+        Command::Fmt { check, wrap } => fmt::cmd_fmt(&cfg, check, wrap),
+        Command::Lsp {} => lsp::cmd_lsp(&cfg),
         Command::SpamMd {} => spam_md::generate_random_markdown_files(Path::new(&"spam"), 100, 100),
     }
 }
+
+/// Split a comma separated config value
+fn split_list(v: &str) -> Vec<String> {
+    v.split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
@@ -0,0 +1,113 @@
+//! Line by line scanning of Markdown
+//!
+//! For rewriting the sources before they are parsed,
+//! where code must be recognized without a full Markdown parser.
+
+/// A fence line like ```` ```rust ````, indented by up to three spaces:
+/// fence character, length, and info string
+pub fn fence(line: &str) -> Option<(char, usize, &str)> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent > 3 {
+        return None;
+    }
+    let line = &line[indent..];
+    let c = line.chars().next()?;
+    if c != '`' && c != '~' {
+        return None;
+    }
+    let len = line.chars().take_while(|x| *x == c).count();
+    if len < 3 {
+        return None;
+    }
+    Some((c, len, line[len..].trim()))
+}
+
+/// Whether a line closes a fenced code block opened with this fence
+pub fn closes_fence(line: &str, c: char, len: usize) -> bool {
+    matches!(fence(line), Some((c2, len2, "")) if c2 == c && len2 >= len)
+}
+
+/// Length of the code span at the start of the text,
+/// which ends with a run of as many backticks as it starts with.
+/// Without one, the length of the opening run, which is plain text.
+pub fn code_span_len(text: &str) -> usize {
+    let run = |s: &str| s.len() - s.trim_start_matches('`').len();
+    let ticks = run(text);
+    let mut pos = ticks;
+    while let Some(i) = text[pos..].find('`') {
+        let start = pos + i;
+        let len = run(&text[start..]);
+        if len == ticks {
+            return start + len;
+        }
+        pos = start + len;
+    }
+    ticks
+}
+
+/// Tracks indented code blocks, fed with every line outside of fenced code
+pub struct IndentedCode {
+    prev_blank: bool,
+    in_code: bool,
+}
+
+impl Default for IndentedCode {
+    fn default() -> IndentedCode {
+        IndentedCode {
+            prev_blank: true,
+            in_code: false,
+        }
+    }
+}
+
+impl IndentedCode {
+    /// Whether the line is part of an indented code block
+    pub fn is_code(&mut self, line: &str) -> bool {
+        let blank = line.trim().is_empty();
+        self.in_code = !blank
+            && (line.starts_with("    ") || line.starts_with('\t'))
+            && (self.prev_blank || self.in_code);
+        self.prev_blank = blank;
+        self.in_code
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fences() {
+        assert_eq!(fence("```rust {1}\n"), Some(('`', 3, "rust {1}")));
+        assert_eq!(fence("~~~~"), Some(('~', 4, "")));
+        assert_eq!(fence("   ```"), Some(('`', 3, "")));
+        assert_eq!(fence("    ```"), None);
+        assert_eq!(fence("``"), None);
+        assert!(closes_fence("````\n", '`', 3));
+        assert!(!closes_fence("``` x\n", '`', 3));
+        assert!(!closes_fence("```\n", '`', 4));
+        assert!(!closes_fence("~~~\n", '`', 3));
+    }
+
+    #[test]
+    fn code_spans() {
+        assert_eq!(code_span_len("`a` b"), 3);
+        assert_eq!(code_span_len("``a`b`` c"), 7);
+        // a closing run must have the same length
+        assert_eq!(code_span_len("`a``b` c"), 6);
+        assert_eq!(code_span_len("``a`b``` c``"), 12);
+        // unclosed, the backticks are text
+        assert_eq!(code_span_len("``a` b"), 2);
+    }
+
+    #[test]
+    fn indented_code() {
+        let mut ic = IndentedCode::default();
+        let code: Vec<bool> = ["    a\n", "text\n", "    b\n", "\n", "\tc\n", "    d\n"]
+            .iter()
+            .map(|l| ic.is_code(l))
+            .collect();
+        // no indented code within a paragraph
+        assert_eq!(code, [true, false, false, false, true, true]);
+    }
+}
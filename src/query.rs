@@ -0,0 +1,439 @@
+//! A small query language to select documents
+//!
+//! Conditions compare a field with a value like `status = accepted`,
+//! `tag != draft`, or `path ~ req*.md` for glob patterns.
+//! They combine with `and`, `or`, `not`, and parentheses.
+
+use std::cmp::Ordering;
+
+/// Access to the fields of a document for queries
+pub trait Fields {
+    /// All values of a field, empty if the document does not have it
+    fn field_values(&self, key: &str) -> Vec<String>;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Glob,
+}
+
+#[derive(Clone, Debug)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Cond { key: String, op: Op, value: String },
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Op(Op),
+    Open,
+    Close,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            _ if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            '=' => {
+                chars.next();
+                tokens.push(Token::Op(Op::Eq));
+            }
+            '~' => {
+                chars.next();
+                tokens.push(Token::Op(Op::Glob));
+            }
+            '!' => {
+                chars.next();
+                match chars.next() {
+                    Some('=') => tokens.push(Token::Op(Op::Ne)),
+                    _ => return Err("expected != ".to_string()),
+                }
+            }
+            '"' => {
+                chars.next();
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated quote".to_string()),
+                    }
+                }
+                tokens.push(Token::Word(word));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "()=!~\"".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct ExprParser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl ExprParser {
+    fn peek_keyword(&self, kw: &str) -> bool {
+        matches!(self.tokens.get(self.pos), Some(Token::Word(w)) if w == kw)
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut left = self.and()?;
+        while self.peek_keyword("or") {
+            self.pos += 1;
+            let right = self.and()?;
+            left = Expr::Or(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut left = self.not()?;
+        while self.peek_keyword("and") {
+            self.pos += 1;
+            let right = self.not()?;
+            left = Expr::And(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expr, String> {
+        if self.peek_keyword("not") {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<Expr, String> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Open) => {
+                self.pos += 1;
+                let e = self.or()?;
+                match self.tokens.get(self.pos) {
+                    Some(Token::Close) => {
+                        self.pos += 1;
+                        Ok(e)
+                    }
+                    _ => Err("missing )".to_string()),
+                }
+            }
+            Some(Token::Word(key)) => {
+                let key = key.clone();
+                let op = match self.tokens.get(self.pos + 1) {
+                    Some(Token::Op(op)) => op.clone(),
+                    _ => return Err(format!("expected operator after {}", key)),
+                };
+                let value = match self.tokens.get(self.pos + 2) {
+                    Some(Token::Word(v)) => v.clone(),
+                    _ => return Err(format!("expected value after {}", key)),
+                };
+                self.pos += 3;
+                Ok(Expr::Cond { key, op, value })
+            }
+            Some(t) => Err(format!("unexpected {:?}", t)),
+            None => Err("unexpected end".to_string()),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(s: &str) -> Result<Expr, String> {
+        let mut p = ExprParser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let e = p.or()?;
+        if p.pos < p.tokens.len() {
+            return Err(format!("unexpected {:?}", p.tokens[p.pos]));
+        }
+        Ok(e)
+    }
+
+    pub fn matches(&self, doc: &impl Fields) -> bool {
+        match self {
+            Expr::And(a, b) => a.matches(doc) && b.matches(doc),
+            Expr::Or(a, b) => a.matches(doc) || b.matches(doc),
+            Expr::Not(a) => !a.matches(doc),
+            Expr::Cond { key, op, value } => {
+                let values = doc.field_values(key);
+                match op {
+                    Op::Eq => values.iter().any(|v| value_eq(v, value)),
+                    Op::Ne => !values.iter().any(|v| value_eq(v, value)),
+                    Op::Glob => values.iter().any(|v| glob_match(value, v)),
+                }
+            }
+        }
+    }
+}
+
+/// Compare a field value with a query value.
+/// Relations like `did:req2` also match the plain DID.
+fn value_eq(field: &str, value: &str) -> bool {
+    field == value || field.strip_prefix("did:") == Some(value)
+}
+
+/// Match text against a pattern where `*` matches anything except `/`,
+/// `**` matches anything, and `?` matches a single character.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    glob_match_at(&p, &t)
+}
+
+fn glob_match_at(p: &[char], t: &[char]) -> bool {
+    match p.first() {
+        None => t.is_empty(),
+        Some('*') => {
+            let any = p.get(1) == Some(&'*');
+            let rest = if any { &p[2..] } else { &p[1..] };
+            for i in 0..=t.len() {
+                if glob_match_at(rest, &t[i..]) {
+                    return true;
+                }
+                if i < t.len() && !any && t[i] == '/' {
+                    return false;
+                }
+            }
+            false
+        }
+        Some('?') => !t.is_empty() && glob_match_at(&p[1..], &t[1..]),
+        Some(c) => t.first() == Some(c) && glob_match_at(&p[1..], &t[1..]),
+    }
+}
+
+/// Order values with numbers first, numerically, and then text.
+/// This must be a total order for sorting.
+pub fn compare_values(a: &str, b: &str) -> Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(x), Ok(y)) => x.total_cmp(&y).then_with(|| a.cmp(b)),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+/// Filter, sort, and limit a list of documents
+pub struct Selection {
+    /// all of them must match
+    pub filters: Vec<Expr>,
+    /// fields to sort by and whether descending
+    pub sort_by: Vec<(String, bool)>,
+    pub limit: Option<usize>,
+}
+
+impl Selection {
+    pub fn new() -> Selection {
+        Selection {
+            filters: vec![],
+            sort_by: vec![],
+            limit: None,
+        }
+    }
+
+    /// Parse a sort specification like `status, -title` or `did desc`
+    pub fn push_sort_by(&mut self, spec: &str) {
+        for part in spec.split(',') {
+            let part = part.trim();
+            if let Some(key) = part.strip_prefix('-') {
+                self.sort_by.push((key.to_string(), true));
+            } else if let Some(key) = part.strip_suffix(" desc") {
+                self.sort_by.push((key.trim().to_string(), true));
+            } else if let Some(key) = part.strip_suffix(" asc") {
+                self.sort_by.push((key.trim().to_string(), false));
+            } else if !part.is_empty() {
+                self.sort_by.push((part.to_string(), false));
+            }
+        }
+    }
+
+    pub fn select<'a, T: Fields>(&self, docs: &'a [T]) -> Vec<&'a T> {
+        let mut ret: Vec<&T> = docs
+            .iter()
+            .filter(|d| self.filters.iter().all(|f| f.matches(*d)))
+            .collect();
+        if !self.sort_by.is_empty() {
+            ret.sort_by(|a, b| {
+                for (key, descending) in &self.sort_by {
+                    let va = a.field_values(key).join(", ");
+                    let vb = b.field_values(key).join(", ");
+                    let o = compare_values(&va, &vb);
+                    if o != Ordering::Equal {
+                        return if *descending { o.reverse() } else { o };
+                    }
+                }
+                Ordering::Equal
+            });
+        }
+        if let Some(n) = self.limit {
+            ret.truncate(n);
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    struct TestDoc(BTreeMap<&'static str, Vec<&'static str>>);
+
+    impl Fields for TestDoc {
+        fn field_values(&self, key: &str) -> Vec<String> {
+            let values = self.0.get(key).cloned().unwrap_or_default();
+            values.into_iter().map(|v| v.to_string()).collect()
+        }
+    }
+
+    fn doc(fields: &[(&'static str, &'static str)]) -> TestDoc {
+        let mut map: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (k, v) in fields {
+            map.entry(k).or_default().push(v);
+        }
+        TestDoc(map)
+    }
+
+    fn matches(expr: &str, d: &TestDoc) -> bool {
+        Expr::parse(expr).unwrap().matches(d)
+    }
+
+    #[test]
+    fn conditions() {
+        let d = doc(&[
+            ("status", "accepted"),
+            ("tag", "safety"),
+            ("tag", "ui"),
+            ("path", "reqs/r1.md"),
+            ("satisfies", "did:r2"),
+        ]);
+        assert!(matches("status = accepted", &d));
+        assert!(!matches("status = draft", &d));
+        assert!(matches("tag = ui", &d));
+        assert!(matches("tag != draft", &d));
+        assert!(!matches("tag != ui", &d));
+        assert!(matches("satisfies = r2", &d));
+        assert!(matches("path ~ reqs/*.md", &d));
+        assert!(!matches("path ~ *.md", &d));
+        assert!(matches("path ~ **.md", &d));
+        assert!(matches("missing != x", &d));
+        assert!(!matches("missing = x", &d));
+    }
+
+    #[test]
+    fn combinations() {
+        let d = doc(&[("status", "draft"), ("title", "Fast startup")]);
+        assert!(matches("status = draft and not status = accepted", &d));
+        assert!(matches("status = accepted or status = draft", &d));
+        assert!(!matches("status = accepted or status = review", &d));
+        assert!(matches(r#"title = "Fast startup""#, &d));
+        // and binds stronger than or
+        assert!(matches("status = draft or status = x and status = y", &d));
+        assert!(!matches(
+            "(status = draft or status = x) and status = y",
+            &d
+        ));
+        assert!(matches("not not status = draft", &d));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for expr in [
+            "",
+            "status",
+            "status =",
+            "status ! draft",
+            "(status = draft",
+            "status = draft)",
+            "status = draft and",
+            r#"title = "unterminated"#,
+        ] {
+            assert!(Expr::parse(expr).is_err(), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("req?.md", "req1.md"));
+        assert!(!glob_match("req?.md", "req10.md"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("a/*", "a/b/c"));
+        assert!(glob_match("a/**", "a/b/c"));
+    }
+
+    #[test]
+    fn compare_numbers_and_text() {
+        assert_eq!(compare_values("9", "10"), Ordering::Less);
+        assert_eq!(compare_values("10", "1a"), Ordering::Less);
+        assert_eq!(compare_values("9", "1a"), Ordering::Less);
+        assert_eq!(compare_values("b", "a"), Ordering::Greater);
+        assert_eq!(compare_values("NaN", "1"), Ordering::Greater);
+        assert_eq!(compare_values("NaN", "NaN"), Ordering::Equal);
+    }
+
+    #[test]
+    fn compare_is_total_order() {
+        let values = [
+            "9", "10", "1a", "", "NaN", "-1", "1.0", "1", "inf", "x", "-0", "0",
+        ];
+        for a in values {
+            assert_eq!(compare_values(a, a), Ordering::Equal);
+            for b in values {
+                assert_eq!(compare_values(a, b), compare_values(b, a).reverse());
+                for c in values {
+                    if compare_values(a, b).is_le() && compare_values(b, c).is_le() {
+                        assert!(compare_values(a, c).is_le(), "{} {} {}", a, b, c);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn select_sorts_and_limits() {
+        let docs = vec![
+            doc(&[("did", "c"), ("priority", "10")]),
+            doc(&[("did", "a"), ("priority", "9")]),
+            doc(&[("did", "b"), ("priority", "high")]),
+            doc(&[("did", "d"), ("priority", "9")]),
+        ];
+        let mut sel = Selection::new();
+        sel.push_sort_by("priority, -did");
+        let dids: Vec<String> = sel
+            .select(&docs)
+            .iter()
+            .map(|d| d.field_values("did").join(""))
+            .collect();
+        assert_eq!(dids, ["d", "a", "c", "b"]);
+        sel.limit = Some(2);
+        sel.filters.push(Expr::parse("priority != high").unwrap());
+        assert_eq!(sel.select(&docs).len(), 2);
+    }
+}
@@ -0,0 +1,216 @@
+//! The query command, printing documents or SQL results
+
+use log::error;
+use serde_json::{Map, Value};
+use sqlite::{Connection, State};
+
+use crate::config::Config;
+use crate::gen_html::{doc2docmeta, read_md_files, Doc};
+use crate::query::{Expr, Fields, Selection};
+
+/// Fields with a single value, printed as string in JSON
+const SINGLE_FIELDS: &[&str] = &[
+    "did",
+    "id",
+    "title",
+    "status",
+    "type",
+    "path",
+    "links",
+    "backlinks",
+];
+
+pub(crate) struct QueryArgs {
+    pub filter: Option<String>,
+    pub sql: Option<String>,
+    pub format: String,
+    pub columns: Option<String>,
+    pub sort_by: Option<String>,
+    pub limit: Option<usize>,
+}
+
+pub(crate) fn cmd_query(cfg: &Config, args: &QueryArgs) {
+    if !["table", "json", "csv", "dids"].contains(&args.format.as_str()) {
+        fail(&format!(
+            "Unknown format: {} (expected table, json, csv, or dids)",
+            args.format
+        ));
+    }
+    let (header, rows) = match &args.sql {
+        Some(sql) => match query_sql(cfg, sql) {
+            Ok(r) => r,
+            Err(e) => fail(&format!("SQL query failed: {}", e)),
+        },
+        None => match query_docs(cfg, args) {
+            Ok(r) => r,
+            Err(e) => fail(&format!("Invalid filter: {}", e)),
+        },
+    };
+    match args.format.as_str() {
+        "table" => print_table(&header, &rows),
+        "json" => print_json(&header, &rows),
+        "csv" => print_csv(&header, &rows),
+        _ => {
+            let Some(i) = header.iter().position(|h| h == "did") else {
+                fail("The dids format needs a did column");
+            };
+            for row in &rows {
+                println!("{}", cell_text(&row[i]));
+            }
+        }
+    }
+}
+
+/// Report an error about the query itself and exit
+fn fail(msg: &str) -> ! {
+    error!("{}", msg);
+    std::process::exit(1);
+}
+
+type Rows = (Vec<String>, Vec<Vec<Value>>);
+
+/// Select documents with a filter expression
+fn query_docs(cfg: &Config, args: &QueryArgs) -> Result<Rows, String> {
+    let mut selection = Selection::new();
+    if let Some(f) = &args.filter {
+        selection.filters.push(Expr::parse(f)?);
+    }
+    if let Some(s) = &args.sort_by {
+        selection.push_sort_by(s);
+    }
+    selection.limit = args.limit;
+    let mut docs: Vec<Doc> = vec![];
+    for src in cfg.get_sources() {
+        read_md_files(&mut docs, src.as_path(), cfg);
+    }
+    let metas = doc2docmeta(&docs, cfg);
+    let header: Vec<String> = match &args.columns {
+        Some(c) => c.split(',').map(|c| c.trim().to_string()).collect(),
+        None => vec!["did".to_string(), "title".to_string(), "status".to_string()],
+    };
+    let rows = selection
+        .select(&metas)
+        .iter()
+        .map(|dm| {
+            header
+                .iter()
+                .map(|col| {
+                    let values = dm.field_values(col);
+                    if SINGLE_FIELDS.contains(&col.as_str()) {
+                        Value::String(values.join(", "))
+                    } else {
+                        Value::Array(values.into_iter().map(Value::String).collect())
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    Ok((header, rows))
+}
+
+/// Run SQL against the database of gen-db
+fn query_sql(cfg: &Config, sql: &str) -> sqlite::Result<Rows> {
+    let db = Connection::open(&cfg.db_path)?;
+    let mut stmt = db.prepare(sql)?;
+    let header = stmt.column_names().to_vec();
+    let mut rows = vec![];
+    while let State::Row = stmt.next()? {
+        let mut row = vec![];
+        for i in 0..header.len() {
+            row.push(match stmt.read::<sqlite::Value, _>(i)? {
+                sqlite::Value::Null => Value::Null,
+                sqlite::Value::Integer(n) => Value::from(n),
+                sqlite::Value::Float(f) => Value::from(f),
+                sqlite::Value::String(s) => Value::String(s),
+                sqlite::Value::Binary(b) => Value::String(String::from_utf8_lossy(&b).to_string()),
+            });
+        }
+        rows.push(row);
+    }
+    Ok((header, rows))
+}
+
+fn cell_text(v: &Value) -> String {
+    match v {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Array(a) => a.iter().map(cell_text).collect::<Vec<_>>().join(", "),
+        v => v.to_string(),
+    }
+}
+
+fn print_table(header: &[String], rows: &[Vec<Value>]) {
+    let cells: Vec<Vec<String>> = rows
+        .iter()
+        .map(|r| r.iter().map(cell_text).collect())
+        .collect();
+    let widths: Vec<usize> = header
+        .iter()
+        .enumerate()
+        .map(|(i, h)| {
+            cells
+                .iter()
+                .map(|r| r[i].chars().count())
+                .max()
+                .unwrap_or(0)
+                .max(h.chars().count())
+        })
+        .collect();
+    let print_row = |row: &[String]| {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{:w$}", c, w = w))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(header);
+    print_row(
+        &widths
+            .iter()
+            .map(|w| "-".repeat(*w))
+            .collect::<Vec<String>>(),
+    );
+    for row in &cells {
+        print_row(row);
+    }
+}
+
+fn print_json(header: &[String], rows: &[Vec<Value>]) {
+    let objects: Vec<Value> = rows
+        .iter()
+        .map(|r| {
+            let mut m = Map::new();
+            for (h, v) in header.iter().zip(r) {
+                m.insert(h.clone(), v.clone());
+            }
+            Value::Object(m)
+        })
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&objects).unwrap());
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn print_csv(header: &[String], rows: &[Vec<Value>]) {
+    let line = |cells: Vec<String>| {
+        println!(
+            "{}",
+            cells
+                .iter()
+                .map(|c| csv_field(c))
+                .collect::<Vec<_>>()
+                .join(",")
+        )
+    };
+    line(header.to_vec());
+    for row in rows {
+        line(row.iter().map(cell_text).collect());
+    }
+}
@@ -0,0 +1,284 @@
+//! Finding and renaming references to a DID in the Markdown sources
+
+use log::{error, info, warn};
+use std::fs;
+use std::ops::Range;
+
+use crate::config::Config;
+use crate::gen_html::{read_all_md_files, Doc};
+use crate::ids;
+use crate::md_lines::{closes_fence, code_span_len, fence, IndentedCode};
+use crate::vars;
+
+pub fn is_did_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_'
+}
+
+/// `did:<did>` in a line, outside of code spans if `spans` is set
+fn find_did_links(line: &str, offset: usize, spans: bool, out: &mut Vec<DidRef>) {
+    let mut pos = 0;
+    while pos < line.len() {
+        let rest = &line[pos..];
+        if spans && rest.starts_with('`') {
+            pos += code_span_len(rest);
+            continue;
+        }
+        if rest.starts_with("did:") && !line[..pos].ends_with(is_did_char) {
+            let did = &rest[4..];
+            let did = &did[..did.find(|c| !is_did_char(c)).unwrap_or(did.len())];
+            if !did.is_empty() {
+                let start = offset + pos + 4;
+                out.push(DidRef {
+                    range: start..start + did.len(),
+                    did: did.to_string(),
+                    is_id: false,
+                });
+                pos += 4 + did.len();
+                continue;
+            }
+        }
+        pos += rest.chars().next().map_or(1, |c| c.len_utf8());
+    }
+}
+
+/// A DID in Markdown
+pub struct DidRef {
+    pub range: Range<usize>,
+    pub did: String,
+    /// the `id:` line in the doc meta info instead of a reference
+    pub is_id: bool,
+}
+
+/// All DIDs in Markdown: links, inclusions, relations,
+/// and `id:` lines in the doc meta info
+pub fn all_did_refs(raw: &str) -> Vec<DidRef> {
+    let mut ret = vec![];
+    // open fenced code block: fence character, length, and whether it is doc meta info
+    let mut code: Option<(char, usize, bool)> = None;
+    let mut indented = IndentedCode::default();
+    let mut offset = 0;
+    for line in raw.split_inclusive('\n') {
+        let line_start = offset;
+        offset += line.len();
+        if let Some((c, len, meta)) = code {
+            if closes_fence(line, c, len) {
+                code = None;
+            } else if meta {
+                if let Some((k, v)) = line.split_once(':') {
+                    if k.trim() == "id" && !v.trim().is_empty() {
+                        let start = line_start + k.len() + 1 + (v.len() - v.trim_start().len());
+                        ret.push(DidRef {
+                            range: start..start + v.trim().len(),
+                            did: v.trim().to_string(),
+                            is_id: true,
+                        });
+                        continue;
+                    }
+                }
+                find_did_links(line, line_start, false, &mut ret);
+            }
+            continue;
+        }
+        if let Some((c, len, info)) = fence(line) {
+            let meta = info == "docdustry-docmeta" || info == "docdustry-doclist";
+            code = Some((c, len, meta));
+            indented.is_code(line);
+            continue;
+        }
+        if !indented.is_code(line) {
+            find_did_links(line, line_start, true, &mut ret);
+        }
+    }
+    ret
+}
+
+/// Byte ranges of all references to a DID in Markdown, including its `id:` line
+pub fn did_refs(raw: &str, did: &str) -> Vec<Range<usize>> {
+    all_did_refs(raw)
+        .into_iter()
+        .filter(|r| r.did == did)
+        .map(|r| r.range)
+        .collect()
+}
+
+/// The Markdown of a document with references to a DID renamed,
+/// and an `id` inserted if it is the document with the derived DID
+pub fn rename_in(raw: &str, doc_did: &str, old: &str, new: &str) -> String {
+    let ret = replace_ranges(raw, &did_refs(raw, old), new);
+    if doc_did == old && !vars::doc_vars(raw).contains_key("id") {
+        ids::insert_id(&ret, new)
+    } else {
+        ret
+    }
+}
+
+/// Replace the ranges, which must be in order
+fn replace_ranges(raw: &str, ranges: &[Range<usize>], new: &str) -> String {
+    let mut ret = String::with_capacity(raw.len());
+    let mut copied = 0;
+    for r in ranges {
+        ret.push_str(&raw[copied..r.start]);
+        ret.push_str(new);
+        copied = r.end;
+    }
+    ret.push_str(&raw[copied..]);
+    ret
+}
+
+/// Line diff by longest common subsequence, each line marked with ' ', '-', or '+'
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(char, &'a str)> {
+    let (n, m) = (old.len(), new.len());
+    // lcs[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut ret = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && old[i] == new[j] {
+            ret.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            ret.push(('-', old[i]));
+            i += 1;
+        } else {
+            ret.push(('+', new[j]));
+            j += 1;
+        }
+    }
+    ret
+}
+
+/// The changed lines, each group headed by its line number in the old file
+fn format_diff(path: &str, old: &str, new: &str) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let mut ret = format!("--- {}\n+++ {}\n", path, path);
+    let mut line = 1;
+    let mut in_hunk = false;
+    for (op, text) in diff_lines(&old, &new) {
+        if op == ' ' {
+            in_hunk = false;
+        } else {
+            if !in_hunk {
+                ret.push_str(&format!("@@ line {}\n", line));
+                in_hunk = true;
+            }
+            ret.push(op);
+            ret.push_str(text);
+            ret.push('\n');
+        }
+        if op != '+' {
+            line += 1;
+        }
+    }
+    ret
+}
+
+pub(crate) fn cmd_rename_did(cfg: &Config, old: &str, new: &str, dry_run: bool) {
+    if new.is_empty() || !new.chars().all(is_did_char) {
+        error!(
+            "Not a valid DID: {} (letters, digits, `-`, and `_` only)",
+            new
+        );
+        std::process::exit(1);
+    }
+    let mut docs: Vec<Doc> = vec![];
+    for src in cfg.get_sources() {
+        read_all_md_files(&mut docs, src.as_path(), cfg);
+    }
+    if docs.iter().any(|d| d.did == new) {
+        error!("DID exists already: {}", new);
+        std::process::exit(1);
+    }
+    if !docs.iter().any(|d| d.did == old) {
+        warn!(
+            "No document has the DID {}, only references are renamed",
+            old
+        );
+    }
+    let mut files = 0;
+    for d in &docs {
+        let raw = rename_in(&d.raw, &d.did, old, new);
+        if raw == d.raw {
+            continue;
+        }
+        files += 1;
+        let path = d.src_path();
+        if dry_run {
+            print!("{}", format_diff(&path.display().to_string(), &d.raw, &raw));
+        } else if let Err(e) = fs::write(&path, raw) {
+            warn!("Failed to write {}: {}", path.display(), e);
+        } else {
+            info!(
+                "Renamed {} references in {}",
+                did_refs(&d.raw, old).len(),
+                path.display()
+            );
+        }
+    }
+    info!("{} files with references to {}", files, old);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAW: &str = "# A\n\nSee [a](did:old), [](did:old#sec), did:older, `did:old`.\n\n    did:old\n\n![x](did:old?notitle)\n\n```docdustry-docmeta\nid: old\nsatisfies: did:old\n```\n";
+
+    #[test]
+    fn references() {
+        let refs = all_did_refs(RAW);
+        let dids: Vec<(&str, bool)> = refs.iter().map(|r| (r.did.as_str(), r.is_id)).collect();
+        assert_eq!(
+            dids,
+            [
+                ("old", false),
+                ("old", false),
+                ("older", false),
+                ("old", false),
+                ("old", true),
+                ("old", false)
+            ]
+        );
+        for r in &refs {
+            assert_eq!(&RAW[r.range.clone()], r.did);
+        }
+    }
+
+    #[test]
+    fn diffs() {
+        let old = "# A\n\nSee did:x.\n\n```docdustry-docmeta\nstatus: draft\n```\n";
+        let new = rename_in(old, "x", "x", "y");
+        assert_eq!(
+            format_diff("a.md", old, &new),
+            "--- a.md\n+++ a.md\n@@ line 3\n-See did:x.\n+See did:y.\n@@ line 6\n+id: y\n"
+        );
+        assert_eq!(
+            format_diff("a.md", "a\n", "a\n\nb\nc\n"),
+            "--- a.md\n+++ a.md\n@@ line 2\n+\n+b\n+c\n"
+        );
+    }
+
+    #[test]
+    fn renaming() {
+        assert_eq!(
+            rename_in(RAW, "old", "old", "new"),
+            "# A\n\nSee [a](did:new), [](did:new#sec), did:older, `did:old`.\n\n    did:old\n\n![x](did:new?notitle)\n\n```docdustry-docmeta\nid: new\nsatisfies: did:new\n```\n"
+        );
+        // a document with a derived DID gets an id
+        let raw = "# A\n\nSee did:x.\n";
+        assert_eq!(
+            rename_in(raw, "x", "x", "y"),
+            "# A\n\nSee did:y.\n\n```docdustry-docmeta\nid: y\n```\n"
+        );
+    }
+}
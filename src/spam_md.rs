@@ -13,9 +13,9 @@ pub fn generate_random_markdown_files(output_dir: &Path, num_dirs: usize, files_
             let file_path = dir_path.join(&file_name);
             let mut file = File::create(&file_path).expect("Failed to create file");
 
-            write!(file, "# Random Markdown {} {}\n", dir_num, file_num)
+            writeln!(file, "# Random Markdown {} {}", dir_num, file_num)
                 .expect("Failed to write to file");
-            file.write(MARKDOWN).expect("Failed to write to file");
+            file.write_all(MARKDOWN).expect("Failed to write to file");
         }
     }
 }
@@ -0,0 +1,135 @@
+//! Checks of document statuses against the configured lifecycles
+
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::gen_html::Doc;
+
+/// Statuses of the previous build, stored in the output directory
+const SNAPSHOT_FILE: &str = "docdustry_status.json";
+
+fn read_snapshot(output_dir: &Path) -> HashMap<String, String> {
+    let path = output_dir.join(SNAPSHOT_FILE);
+    let content = match fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(_) => {
+            info!("No previous statuses: {}", path.display());
+            return HashMap::new();
+        }
+    };
+    match serde_json::from_str(&content) {
+        Ok(m) => m,
+        Err(e) => {
+            warn!("Invalid status snapshot {}: {}", path.display(), e);
+            HashMap::new()
+        }
+    }
+}
+
+pub fn write_snapshot(output_dir: &Path, docs: &[Doc]) -> Result<(), std::io::Error> {
+    let map: HashMap<&str, &str> = docs
+        .iter()
+        .filter(|d| !d.status.is_empty())
+        .map(|d| (d.did.as_str(), d.status.as_str()))
+        .collect();
+    fs::write(output_dir.join(SNAPSHOT_FILE), serde_json::to_string(&map)?)
+}
+
+/// Warn about unknown statuses and status changes not allowed since the previous build
+pub fn check_lifecycles(docs: &[Doc], cfg: &Config) {
+    let previous = read_snapshot(&cfg.output);
+    for d in docs {
+        if d.status.is_empty() {
+            continue;
+        }
+        let lc = match cfg.lifecycle(&d.doc_type) {
+            Some(lc) => lc,
+            None => continue,
+        };
+        if !lc.statuses.contains(&d.status) {
+            warn!(
+                "Unknown status of {}: {} (expected one of {})",
+                d.did,
+                d.status,
+                lc.statuses.join(", ")
+            );
+            continue;
+        }
+        if let Some(prev) = previous.get(&d.did) {
+            if !lc.allows(prev, &d.status) {
+                warn!(
+                    "Status change of {} not allowed: {} -> {}",
+                    d.did, prev, d.status
+                );
+            }
+        }
+    }
+}
+
+/// DIDs of documents linked or included by d, each once
+fn references(d: &Doc) -> Vec<(&'static str, &str)> {
+    let links = d
+        .links
+        .iter()
+        .filter_map(|l| l.strip_prefix("did:"))
+        .map(|l| ("links to", l.split('#').next().unwrap()));
+    let includes = d.includes.iter().map(|l| ("includes", l.as_str()));
+    let mut ret: Vec<(&str, &str)> = vec![];
+    for (verb, did) in links.chain(includes) {
+        if !ret.iter().any(|(_, x)| *x == did) {
+            ret.push((verb, did));
+        }
+    }
+    ret
+}
+
+/// Whether the status of a document is configured and not released, like draft or obsolete
+fn is_unreleased(d: &Doc, cfg: &Config) -> bool {
+    if d.status.is_empty() || cfg.is_released(&d.status) {
+        return false;
+    }
+    cfg.is_inactive(&d.status)
+        || cfg
+            .lifecycle(&d.doc_type)
+            .is_some_and(|lc| lc.statuses.contains(&d.status))
+}
+
+/// Warn if a released document links to or includes an unreleased one
+pub fn check_released_links(docs: &[Doc], cfg: &Config) {
+    let by_did: HashMap<&str, &Doc> = docs.iter().map(|d| (d.did.as_str(), d)).collect();
+    for d in docs {
+        if !cfg.is_released(&d.status) {
+            continue;
+        }
+        for (verb, did) in references(d) {
+            if let Some(target) = by_did.get(did) {
+                if is_unreleased(target, cfg) {
+                    warn!(
+                        "{} ({}) {} {} ({})",
+                        d.did, d.status, verb, did, target.status
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Warn if an active document links to or includes an inactive one
+pub fn check_inactive_links(docs: &[Doc], cfg: &Config) {
+    let by_did: HashMap<&str, &Doc> = docs.iter().map(|d| (d.did.as_str(), d)).collect();
+    for d in docs {
+        if cfg.is_inactive(&d.status) {
+            continue;
+        }
+        for (verb, did) in references(d) {
+            if let Some(target) = by_did.get(did) {
+                if cfg.is_inactive(&target.status) {
+                    warn!("{} {} {} document {}", d.did, verb, target.status, did);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Variables like `{{product_name}}` in documents
+//!
+//! Values come from the doc meta info of the document
+//! or the `[vars]` section of the config.
+//! Code is left alone, except fenced code blocks with the `vars` attribute.
+
+use log::error;
+use std::collections::BTreeMap;
+
+use crate::config::Config;
+use crate::gen_html::CodeInfo;
+use crate::md_lines::{closes_fence, code_span_len, fence, IndentedCode};
+
+/// Fields of the doc meta info, usable as variables in the document
+pub fn doc_vars(raw: &str) -> BTreeMap<String, String> {
+    let mut ret = BTreeMap::new();
+    let mut in_meta = false;
+    for line in raw.lines() {
+        if in_meta {
+            if line.starts_with("```") {
+                in_meta = false;
+            } else if let Some((k, v)) = line.split_once(':') {
+                let k = k.trim();
+                if k != "tag" {
+                    ret.insert(k.to_string(), v.trim().to_string());
+                }
+            }
+        } else if line.trim_end() == "```docdustry-docmeta" {
+            in_meta = true;
+        }
+    }
+    ret
+}
+
+/// Replace the variables in Markdown, adding the undefined ones to `undefined`
+pub fn substitute(
+    raw: &str,
+    doc_vars: &BTreeMap<String, String>,
+    cfg: &Config,
+    did: &str,
+    undefined: &mut Vec<String>,
+) -> String {
+    if !raw.contains("{{") {
+        return raw.to_string();
+    }
+    let lookup = |name: &str| doc_vars.get(name).or_else(|| cfg.vars.get(name));
+    let mut ret = String::with_capacity(raw.len());
+    // open fenced code block: fence character, length, and whether to substitute
+    let mut code: Option<(char, usize, bool)> = None;
+    let mut indented = IndentedCode::default();
+    for line in raw.split_inclusive('\n') {
+        if let Some((c, len, subst)) = code {
+            if closes_fence(line, c, len) {
+                code = None;
+                ret.push_str(line);
+            } else if subst {
+                substitute_line(line, &lookup, did, false, undefined, &mut ret);
+            } else {
+                ret.push_str(line);
+            }
+            continue;
+        }
+        if let Some((c, len, info)) = fence(line) {
+            let subst = info.contains("vars") && CodeInfo::parse(info).vars;
+            code = Some((c, len, subst));
+            ret.push_str(line);
+            indented.is_code(line);
+            continue;
+        }
+        if indented.is_code(line) {
+            ret.push_str(line);
+        } else {
+            substitute_line(line, &lookup, did, true, undefined, &mut ret);
+        }
+    }
+    ret
+}
+
+/// Replace the variables in a line, outside of code spans if `spans` is set
+fn substitute_line<'a>(
+    line: &str,
+    lookup: &impl Fn(&str) -> Option<&'a String>,
+    did: &str,
+    spans: bool,
+    undefined: &mut Vec<String>,
+    ret: &mut String,
+) {
+    let mut rest = line;
+    while !rest.is_empty() {
+        let next = rest.find(['{', '`', '\\']).unwrap_or(rest.len());
+        ret.push_str(&rest[..next]);
+        rest = &rest[next..];
+        if rest.starts_with('\\') {
+            // escaped like \{{name}}
+            let len = rest.chars().nth(1).map_or(1, |c| 1 + c.len_utf8());
+            ret.push_str(&rest[..len]);
+            rest = &rest[len..];
+        } else if spans && rest.starts_with('`') {
+            let end = code_span_len(rest);
+            ret.push_str(&rest[..end]);
+            rest = &rest[end..];
+        } else if let Some(inner) = rest.strip_prefix("{{") {
+            match inner.find("}}") {
+                Some(end) => {
+                    let name = inner[..end].trim();
+                    match lookup(name) {
+                        Some(v) => ret.push_str(v),
+                        None => {
+                            error!("Undefined variable in {}: {}", did, name);
+                            undefined.push(name.to_string());
+                            ret.push_str(&rest[..end + 4]);
+                        }
+                    }
+                    rest = &inner[end + 2..];
+                }
+                None => {
+                    ret.push_str("{{");
+                    rest = inner;
+                }
+            }
+        } else if !rest.is_empty() {
+            // a single { or a backtick inside code
+            ret.push_str(&rest[..1]);
+            rest = &rest[1..];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subst(raw: &str) -> (String, Vec<String>) {
+        let mut cfg = Config::new();
+        cfg.vars.insert("name".to_string(), "Dusty".to_string());
+        cfg.vars.insert("id".to_string(), "none".to_string());
+        let mut undefined = vec![];
+        let ret = substitute(raw, &doc_vars(raw), &cfg, "test", &mut undefined);
+        (ret, undefined)
+    }
+
+    #[test]
+    fn doc_meta_fields() {
+        let vars = doc_vars("# T\n\n```docdustry-docmeta\nid: a\ntag: x\nowner: Bob\n```\n");
+        assert_eq!(vars.get("id").map(|s| s.as_str()), Some("a"));
+        assert_eq!(vars.get("owner").map(|s| s.as_str()), Some("Bob"));
+        assert!(!vars.contains_key("tag"));
+    }
+
+    #[test]
+    fn substitution() {
+        let (ret, undefined) = subst("{{ name }} {{id}} {{nope}} \\{{name}}\n");
+        assert_eq!(ret, "Dusty none {{nope}} \\{{name}}\n");
+        assert_eq!(undefined, ["nope"]);
+        // the doc meta info overrides the config
+        let (ret, _) = subst("{{id}}\n```docdustry-docmeta\nid: x\n```\n");
+        assert!(ret.starts_with("x\n"));
+    }
+
+    #[test]
+    fn code() {
+        let raw = "`{{name}}` ``a`{{name}}`` {{name}}\n\n    {{name}}\n\n```\n{{name}}\n```\n";
+        let (ret, undefined) = subst(raw);
+        assert_eq!(
+            ret,
+            "`{{name}}` ``a`{{name}}`` Dusty\n\n    {{name}}\n\n```\n{{name}}\n```\n"
+        );
+        assert!(undefined.is_empty());
+        let (ret, _) = subst("```sh vars\necho {{name}}\n```\n");
+        assert_eq!(ret, "```sh vars\necho Dusty\n```\n");
+    }
+}